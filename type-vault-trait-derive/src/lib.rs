@@ -3,11 +3,13 @@ use quote::quote;
 use syn::*;
 use std::{iter::zip, vec};
 
-#[proc_macro_derive(VaultType)]
+#[proc_macro_derive(VaultType, attributes(vault))]
 pub fn replace_with_value_id(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
   let input = parse_macro_input!(input as DeriveInput);
   let name = input.ident;
+  let vis = input.vis;
   let new_name = Ident::new(&format!("{}Fact", name), name.span());
+  let type_name = vault_type_name(&input.attrs, &name);
 
   match input.data {
     Data::Union(_) => panic!("VaultType can not be derived with unions."),
@@ -22,15 +24,17 @@ pub fn replace_with_value_id(input: proc_macro::TokenStream) -> proc_macro::Toke
       let mut deserialize_fields = Vec::new();
       let mut build_variants = Vec::new();
       let mut assign_new_variants = Vec::new();
+      let mut variant_schemas = Vec::new();
+      let mut remap_fields = Vec::new();
 
       fn serialize_into_fields_fn(is_modified_field: &Vec<bool>, field_vars: &Vec<Ident>, pattern_vars: &Vec<Ident>) -> Vec<TokenStream> {
         zip(is_modified_field, zip(field_vars, pattern_vars)).map(|(is_modified, (field_var, pattern_var))| {
           if *is_modified {
             quote! {
               let mut dest_nested = vec![];
-              #pattern_var.serialize_into(nested_dest, &mut dest_nested, type_map);
-              let #field_var = value_id_of(&dest_nested);
-              nested_dest.push((dest_nested, #field_var));
+              #pattern_var.serialize_into(nested_dest, &mut dest_nested, type_map)?;
+              let #field_var = type_map.value_id_of(&dest_nested);
+              nested_dest.push(dest_nested, #field_var);
             }
           } else {
             quote! {
@@ -46,29 +50,31 @@ pub fn replace_with_value_id(input: proc_macro::TokenStream) -> proc_macro::Toke
             quote! {
               let mut dest = vec![];
               // Ignore the nested fields. We only care about the hash.
-              #pattern_var.serialize_into(&mut vec![], &mut dest, type_map);
-              result.extend(bincode::serde::encode_to_vec(value_id_of(&dest), BINCODE_CONFIG).unwrap());
+              #pattern_var.serialize_into(&mut NestedValues::new(), &mut dest, type_map)?;
+              result.extend(bincode::serde::encode_to_vec(type_map.value_id_of(&dest), BINCODE_CONFIG)?);
             }
           } else {
             quote! {
-              result.extend(bincode::serde::encode_to_vec(#pattern_var, BINCODE_CONFIG).unwrap());
+              result.extend(encode_key(#pattern_var)?);
             }
           }
         }).collect()
       }
 
-      fn deserialize_fields_fn(name: &Ident, is_modified_fields: &Vec<bool>, field_vars: &Vec<Ident>, pattern_vars: &Vec<Ident>, field_types: &Vec<Type>, field_members: &Vec<Member>) -> Vec<TokenStream> {
-        zip(is_modified_fields, zip(field_vars, zip(pattern_vars, zip(field_types, field_members)))).map(|(is_modified, (field_var, (pattern_var, (field_type, field_member))))| {
+      fn remap_fields_fn(is_modified_fields: &Vec<bool>, pattern_vars: &Vec<Ident>) -> Vec<TokenStream> {
+        zip(is_modified_fields, pattern_vars).filter(|(is_modified, _)| **is_modified).map(|(_, pattern_var)| {
+          quote! {
+            *#pattern_var = remap(*#pattern_var)?;
+          }
+        }).collect()
+      }
+
+      fn deserialize_fields_fn(is_modified_fields: &Vec<bool>, field_vars: &Vec<Ident>, pattern_vars: &Vec<Ident>, field_types: &Vec<Type>) -> Vec<TokenStream> {
+        zip(is_modified_fields, zip(field_vars, zip(pattern_vars, field_types))).map(|(is_modified, (field_var, (pattern_var, field_type)))| {
           if *is_modified {
             quote! {
-              let nested_data = match lookup_id(#field_member) {
-                None => {
-                  eprintln!("Failed to look up ID {:?} for field {} of struct {}", new_struct.#field_member, stringify!(#field_member), stringify!(#name));
-                  return None
-                },
-                Some(data) => data,
-              };
-              let #field_var : #field_type = deserialize_type::<#field_type>(&nested_data, lookup_id)?;
+              let nested_data = lookup_id(#pattern_var)?.ok_or(VaultError::MissingId(#pattern_var))?;
+              let #field_var : #field_type = deserialize_type::<#field_type>(&nested_data, type_map, lookup_id)?;
             }
           } else {
             quote! {
@@ -81,6 +87,14 @@ pub fn replace_with_value_id(input: proc_macro::TokenStream) -> proc_macro::Toke
       for variant in enums.variants {
         let variant_name = variant.ident;
         variant_names.push(variant_name.clone());
+        if variant.fields.iter().any(is_indexed) {
+          panic!("Only fields of structs can be indexed, not those of {}::{}.", name, variant_name);
+        }
+        let variant_fields = fields_schema(&variant.fields);
+        let variant_name_string = variant_name.to_string();
+        variant_schemas.push(quote! {
+          VariantSchema { name: #variant_name_string.to_owned(), fields: #variant_fields }
+        });
         match variant.fields {
           Fields::Unit => {
             variant_types.push(quote! { #variant_name() });
@@ -89,6 +103,7 @@ pub fn replace_with_value_id(input: proc_macro::TokenStream) -> proc_macro::Toke
             all_field_vars.push(vec![]);
             serialize_fields.push(serialize_fields_fn(&vec![], &vec![]));
             deserialize_fields.push(vec![]);
+            remap_fields.push(vec![]);
             build_variants.push(quote! {
                 Self::#variant_name ()
             });
@@ -98,20 +113,22 @@ pub fn replace_with_value_id(input: proc_macro::TokenStream) -> proc_macro::Toke
           },
           Fields::Unnamed(unnamed_fields) => {
             let NewFieldsInfo {
-              field_members,
               field_types,
+              new_field_types,
+              new_field_attrs,
               field_vars,
               pattern_vars,
               is_modified_field,
               modified_field_types,
               ..
             } = convert_unnamed_fields(&new_name, &unnamed_fields);
-            variant_types.push(quote! { #variant_name ( #(#field_types),* ) });
+            variant_types.push(quote! { #variant_name ( #(#new_field_attrs #new_field_types),* ) });
             variant_patterns.push(quote! { #variant_name ( #(#pattern_vars),* ) } );
             modified_variant_field_types.extend(modified_field_types);
             serialize_into_fields.push(serialize_into_fields_fn(&is_modified_field, &field_vars, &pattern_vars));
             serialize_fields.push(serialize_fields_fn(&is_modified_field, &pattern_vars));
-            deserialize_fields.push(deserialize_fields_fn(&name, &is_modified_field, &field_vars, &pattern_vars, &field_types, &field_members));
+            deserialize_fields.push(deserialize_fields_fn(&is_modified_field, &field_vars, &pattern_vars, &field_types));
+            remap_fields.push(remap_fields_fn(&is_modified_field, &pattern_vars));
             all_field_vars.push(field_vars.clone());
             build_variants.push(quote! {
                 Self::#variant_name ( #(#field_vars),* )
@@ -124,18 +141,21 @@ pub fn replace_with_value_id(input: proc_macro::TokenStream) -> proc_macro::Toke
             let NewFieldsInfo {
               field_members,
               field_types,
+              new_field_types,
+              new_field_attrs,
               field_vars,
               pattern_vars,
               is_modified_field,
               modified_field_types,
               ..
             } = convert_named_fields(&new_name, &named_fields);
-            variant_types.push(quote! { #variant_name { #(#field_members : #field_types),* } });
+            variant_types.push(quote! { #variant_name { #(#new_field_attrs #field_members : #new_field_types),* } });
             variant_patterns.push(quote! { #variant_name { #(#field_members : #pattern_vars),* } } );
             modified_variant_field_types.extend(modified_field_types);
             serialize_into_fields.push(serialize_into_fields_fn(&is_modified_field, &field_vars, &pattern_vars));
             serialize_fields.push(serialize_fields_fn(&is_modified_field, &pattern_vars));
-            deserialize_fields.push(deserialize_fields_fn(&name, &is_modified_field, &field_vars, &pattern_vars, &field_types, &field_members));
+            deserialize_fields.push(deserialize_fields_fn(&is_modified_field, &field_vars, &pattern_vars, &field_types));
+            remap_fields.push(remap_fields_fn(&is_modified_field, &pattern_vars));
             all_field_vars.push(field_vars.clone());
             build_variants.push(quote! {
                 Self::#variant_name { #(#field_members : #field_vars),* }
@@ -157,26 +177,43 @@ pub fn replace_with_value_id(input: proc_macro::TokenStream) -> proc_macro::Toke
 
         impl VaultType for #name {
           type InnerVaultType = (#new_name #(,#modified_variant_field_types)*);
+          type Fact = #new_name;
+          const TYPE_NAME: &'static str = #type_name;
+
+          fn schema() -> Schema {
+            Schema::Enum(vec![#(#variant_schemas),*])
+          }
+
+          fn stored_type_name() -> String {
+            Self::TYPE_NAME.to_owned()
+          }
+
+          fn encoded_fields(_fact: &[u8]) -> Result<Vec<Vec<u8>>, VaultError> {
+            Ok(vec![])
+          }
+
+          fn indexed_fields(_fact: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, VaultError> {
+            Ok(vec![])
+          }
 
-          fn serialize_into(&self, _nested_dest: &mut Vec<(Vec<u8>, ValueId)>, dest: &mut Vec<u8>, type_map: &TypeMap) {
-            dest.append(&mut type_map.get(&TypeId::of::<Self>()).expect("Type not registered in type map").to_owned());
+          #[allow(unused_variables)]
+          fn serialize_into(&self, nested_dest: &mut NestedValues, dest: &mut Vec<u8>, type_map: &TypeMap) -> Result<(), VaultError> {
+            dest.append(&mut type_map.tag_of::<Self>()?);
             match self {
               #(Self::#variant_patterns => {
                 #(
                   #serialize_into_fields
                 )*
                 let variant = #assign_new_variants;
-                let mut serialized = match bincode::serde::encode_to_vec(&variant, BINCODE_CONFIG) {
-                  Ok(vec) => vec,
-                  Err(err) => panic!("bincode failed with: {:?}", err),
-                };
+                let mut serialized = bincode::serde::encode_to_vec(&variant, BINCODE_CONFIG)?;
                 dest.append(&mut serialized);
               }),*
             }
+            Ok(())
           }
 
-          fn serialize_prefix(&self, fields_in_prefix: u64, type_map: &TypeMap) -> Vec<u8> {
-            let mut result = type_map.get(&TypeId::of::<Self>()).expect("Type not registered in type map").to_owned();
+          fn serialize_prefix(&self, fields_in_prefix: u64, type_map: &TypeMap) -> Result<Vec<u8>, VaultError> {
+            let mut result = type_map.tag_of::<Self>()?;
             match self {
               #(Self::#variant_patterns => {
                 // TODO: We're not encoding the variant discriminator here. I need to look again at bincode.
@@ -185,58 +222,129 @@ pub fn replace_with_value_id(input: proc_macro::TokenStream) -> proc_macro::Toke
                   if remaining_fields > 0 {
                     #serialize_fields
                   } else {
-                    return result;
+                    return Ok(result);
                   }
                 )*
-                result
+                Ok(result)
               }),*
             }
           }
 
-          fn deserialize_value<'a>(data: &'a [u8], lookup_id: &dyn Fn(ValueId) -> Option<Vec<u8>>) -> Option<(&'a [u8],Self)> where Self: Sized {
+          fn deserialize_value<'a>(data: &'a [u8], type_map: &TypeMap, lookup_id: &dyn Fn(ValueId) -> Result<Option<Vec<u8>>, VaultError>) -> Result<(&'a [u8],Self), VaultError> where Self: Sized {
+            let fact = type_map.strip_tag::<Self>(data)?;
             let (new_struct, bytes_consumed): (#new_name, _) =
-              //TODO: Check that the type ID matches
-              match bincode::serde::decode_from_slice(&data[1..], BINCODE_CONFIG) {
-                Err(_) => {
-                  eprintln!("Failed to decode struct of type {}, data: {:?}", stringify!(#new_name), &data);
-                  return None
-                },
-                Ok((strct, bytes_consumed)) => (strct, bytes_consumed),
-            };
+              bincode::serde::decode_from_slice(fact, BINCODE_CONFIG)?;
             match new_struct {
               #(#new_name::#variant_patterns => {
                 #(
                   #deserialize_fields
                 )*
-                Some((
-                  &data[bytes_consumed..],
+                Ok((
+                  &fact[bytes_consumed..],
                   #build_variants
                 ))
               }),*
             }
           }
+
+          #[allow(unused_variables)]
+          fn remap_fact_ids(data: &[u8], remap: &mut dyn FnMut(ValueId) -> Result<ValueId, VaultError>) -> Result<(Vec<u8>, usize), VaultError> {
+            let (mut fact, bytes_consumed): (#new_name, _) = bincode::serde::decode_from_slice(data, BINCODE_CONFIG)?;
+            match &mut fact {
+              #(#new_name::#variant_patterns => {
+                #(
+                  #remap_fields
+                )*
+              }),*
+            }
+            Ok((bincode::serde::encode_to_vec(&fact, BINCODE_CONFIG)?, bytes_consumed))
+          }
         }
       });
 
       tokens
     },
     Data::Struct(data_struct) => {
+      let fields = fields_schema(&data_struct.fields);
+      let encoded_fields = if data_struct.fields.is_empty() {
+        quote! {
+          fn encoded_fields(_fact: &[u8]) -> Result<Vec<Vec<u8>>, VaultError> {
+            Ok(vec![])
+          }
+        }
+      } else {
+        let encode_fields = data_struct.fields.iter().enumerate().map(|(i, field)| {
+          let member = field.ident.clone().map_or_else(|| Member::from(i), Member::Named);
+          if is_primitive_type(&field.ty) {
+            quote!(encode_key(&fact.#member)?)
+          } else {
+            quote!(bincode::serde::encode_to_vec(fact.#member, BINCODE_CONFIG)?)
+          }
+        });
+        quote! {
+          fn encoded_fields(fact: &[u8]) -> Result<Vec<Vec<u8>>, VaultError> {
+            let (fact, _): (#new_name, _) = bincode::serde::decode_from_slice(fact, BINCODE_CONFIG)?;
+            Ok(vec![#(#encode_fields),*])
+          }
+        }
+      };
+      let indexed_positions: Vec<usize> = data_struct.fields.iter().enumerate()
+        .filter(|(_, field)| is_indexed(field))
+        .map(|(i, _)| i)
+        .collect();
+      let indexed_fields = if indexed_positions.is_empty() {
+        quote! {
+          fn indexed_fields(_fact: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, VaultError> {
+            Ok(vec![])
+          }
+        }
+      } else {
+        quote! {
+          fn indexed_fields(fact: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, VaultError> {
+            let mut fields = Self::encoded_fields(fact)?;
+            Ok(vec![#((#indexed_positions as u64, std::mem::take(&mut fields[#indexed_positions]))),*])
+          }
+        }
+      };
+      let type_consts = quote! {
+        const TYPE_NAME: &'static str = #type_name;
+
+        fn schema() -> Schema {
+          Schema::Struct(#fields)
+        }
+
+        fn stored_type_name() -> String {
+          Self::TYPE_NAME.to_owned()
+        }
+
+        #encoded_fields
+
+        #indexed_fields
+      };
       match data_struct.fields {
         Fields::Unit =>
           proc_macro::TokenStream::from(quote! {
             impl VaultType for #name {
               type InnerVaultType = (#name);
+              // Unit structs are stored as nothing but their tag.
+              type Fact = ();
+              #type_consts
+
+              fn serialize_into(&self, _nested_dest: &mut NestedValues, dest: &mut Vec<u8>, type_map: &TypeMap) -> Result<(), VaultError> {
+                dest.append(&mut type_map.tag_of::<Self>()?);
+                Ok(())
+              }
 
-              fn serialize_into(&self, _nested_dest: &mut Vec<(Vec<u8>, ValueId)>, dest: &mut Vec<u8>, type_map: &TypeMap) {
-                dest.append(&mut type_map.get(&TypeId::of::<Self>()).expect("Type not registered in type map").to_owned());
+              fn serialize_prefix(&self, _fields_in_prefix: u64, type_map: &TypeMap) -> Result<Vec<u8>, VaultError> {
+                type_map.tag_of::<Self>()
               }
 
-              fn serialize_prefix(&self, fields_in_prefix: u64, type_map: &TypeMap) -> Vec<u8> {
-                return type_map.get(&TypeId::of::<Self>()).expect("Type not registered in type map").to_owned();
+              fn deserialize_value<'a>(data: &'a [u8], type_map: &TypeMap, _lookup_id: &dyn Fn(ValueId) -> Result<Option<Vec<u8>>, VaultError>) -> Result<(&'a [u8],Self), VaultError> where Self: Sized {
+                Ok((type_map.strip_tag::<Self>(data)?, Self {}))
               }
 
-              fn deserialize_value<'a>(data: &'a [u8], _lookup_id: &dyn Fn(ValueId) -> Option<Vec<u8>>) -> Option<(&'a [u8],Self)> where Self: Sized {
-                Some((&data[1..], Self {}))
+              fn remap_fact_ids(_data: &[u8], _remap: &mut dyn FnMut(ValueId) -> Result<ValueId, VaultError>) -> Result<(Vec<u8>, usize), VaultError> {
+                Ok((vec![], 0))
               }
             }
           }
@@ -255,7 +363,7 @@ pub fn replace_with_value_id(input: proc_macro::TokenStream) -> proc_macro::Toke
             ..
           } = convert_unnamed_fields(&new_name, &unnamed_fields);
 
-          create_vault_type_instance_for_struct(&name,new_name, make_struct, &field_vars, field_types, &field_members,
+          create_vault_type_instance_for_struct(&name,new_name, type_consts, make_struct, &field_vars, field_types, &field_members,
             &is_modified_field,
             modified_field_types, assign_new_struct,
             build_struct)
@@ -275,16 +383,170 @@ pub fn replace_with_value_id(input: proc_macro::TokenStream) -> proc_macro::Toke
             ..
           } = convert_named_fields(&new_name, &named_fields);
 
-          create_vault_type_instance_for_struct(&name,new_name, make_struct, &field_vars, field_types, &field_members,
+          let query = query_builder(&vis, &name, &named_fields, &is_modified_field);
+          let mut tokens = create_vault_type_instance_for_struct(&name,new_name, type_consts, make_struct, &field_vars, field_types, &field_members,
             &is_modified_field,
             modified_field_types, assign_new_struct,
-            build_struct)
+            build_struct);
+          tokens.extend(proc_macro::TokenStream::from(query));
+          tokens
         }
       }
     }
   }
 }
 
+// The name a type is registered under in a vault, which is its identifier unless overridden with
+// `#[vault(name = "...")]`, e.g. to keep the stored values of a type that was renamed.
+fn vault_type_name(attrs: &[Attribute], name: &Ident) -> String {
+  let mut type_name = name.to_string();
+  for attr in attrs.iter().filter(|attr| attr.path().is_ident("vault")) {
+    attr.parse_nested_meta(|meta| {
+      if meta.path.is_ident("name") {
+        type_name = meta.value()?.parse::<LitStr>()?.value();
+        Ok(())
+      } else {
+        Err(meta.error("unsupported vault attribute"))
+      }
+    }).unwrap_or_else(|err| panic!("Invalid vault attribute on {}: {}", name, err));
+  }
+  type_name
+}
+
+// Whether the field is marked `#[vault(index)]`, to have vaults keep an index of its values.
+fn is_indexed(field: &Field) -> bool {
+  let mut indexed = false;
+  for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("vault")) {
+    attr.parse_nested_meta(|meta| {
+      if meta.path.is_ident("index") {
+        indexed = true;
+        Ok(())
+      } else {
+        Err(meta.error("unsupported vault attribute"))
+      }
+    }).unwrap_or_else(|err| panic!("Invalid vault attribute on field {}: {}", quote!(#field), err));
+  }
+  indexed
+}
+
+// Builds the `FieldSchema`s describing how the fields of a struct or variant are stored.
+fn fields_schema(fields: &Fields) -> TokenStream {
+  let field_schemas = fields.iter().enumerate().map(|(i, field)| {
+    let name = field.ident.as_ref().map_or_else(|| i.to_string(), |ident| ident.to_string());
+    let ty = &field.ty;
+    let ty_string = quote!(#ty).to_string().split_whitespace().collect::<String>();
+    let indexed = is_indexed(field);
+    if is_primitive_type(ty) {
+      quote! {
+        FieldSchema { name: #name.to_owned(), ty: #ty_string.to_owned(), storage: FieldStorage::Inline, indexed: #indexed }
+      }
+    } else {
+      quote! {
+        FieldSchema { name: #name.to_owned(), ty: <#ty as VaultType>::stored_type_name(), storage: FieldStorage::ById, indexed: #indexed }
+      }
+    }
+  });
+  quote!(vec![#(#field_schemas),*])
+}
+
+// Generates `<Name>Query`, which constrains any subset of the fields of a struct with named fields
+// and is created with `<Name>::query()`. Fields that are stored by id can be constrained by value,
+// by id with `<field>_id`, or by a query on the values they refer to with `<field>_matching`. It is
+// as visible as the struct, since it refers to the field types.
+fn query_builder(vis: &Visibility, name: &Ident, named_fields: &FieldsNamed, is_modified_field: &[bool]) -> TokenStream {
+  let query_name = Ident::new(&format!("{}Query", name), name.span());
+  let field_idents: Vec<&Ident> = named_fields.named.iter().map(|field| field.ident.as_ref().unwrap()).collect();
+  let mut constraint_types = Vec::new();
+  let mut setters = Vec::new();
+  let mut encode_constraints = Vec::new();
+  for (field, is_modified) in zip(&named_fields.named, is_modified_field) {
+    let ident = field.ident.as_ref().unwrap();
+    let ty = &field.ty;
+    if *is_modified {
+      // Boxes and shared values are stored as their contents, so constrain those.
+      let ty = unboxed_type(ty);
+      let id_setter = Ident::new(&format!("{}_id", ident), ident.span());
+      let matching_setter = Ident::new(&format!("{}_matching", ident), ident.span());
+      constraint_types.push(quote!(ByIdConstraint<'q, #ty>));
+      setters.push(quote! {
+        #vis fn #ident(mut self, value: &'q #ty) -> Self {
+          self.#ident = Some(ByIdConstraint::Value(value));
+          self
+        }
+
+        #vis fn #id_setter(mut self, id: ValueId) -> Self {
+          self.#ident = Some(ByIdConstraint::Id(id));
+          self
+        }
+
+        #vis fn #matching_setter(mut self, query: impl VaultQuery<Target = #ty> + 'q) -> Self {
+          self.#ident = Some(ByIdConstraint::Matching(Box::new(query)));
+          self
+        }
+      });
+      encode_constraints.push(quote! {
+        match &self.#ident {
+          Some(constraint) => constraint.constraint(type_map)?,
+          None => FieldConstraint::Any,
+        }
+      });
+    } else {
+      constraint_types.push(quote!(#ty));
+      setters.push(quote! {
+        #vis fn #ident(mut self, value: #ty) -> Self {
+          self.#ident = Some(value);
+          self
+        }
+      });
+      encode_constraints.push(quote! {
+        match &self.#ident {
+          Some(value) => FieldConstraint::Equals(encode_key(value)?),
+          None => FieldConstraint::Any,
+        }
+      });
+    }
+  }
+  quote! {
+    #[derive(Default)]
+    #vis struct #query_name<'q> {
+      #(#field_idents : Option<#constraint_types>,)*
+      _values: std::marker::PhantomData<&'q ()>,
+    }
+
+    impl<'q> #query_name<'q> {
+      #(#setters)*
+    }
+
+    impl #name {
+      #vis fn query<'q>() -> #query_name<'q> {
+        #query_name::default()
+      }
+    }
+
+    impl VaultQuery for #query_name<'_> {
+      type Target = #name;
+
+      fn field_constraints(&self, type_map: &TypeMap) -> Result<Vec<FieldConstraint<'_>>, VaultError> {
+        Ok(vec![#(#encode_constraints),*])
+      }
+    }
+  }
+}
+
+fn unboxed_type(ty: &Type) -> &Type {
+  if let Type::Path(type_path) = ty {
+    let last = type_path.path.segments.last().unwrap();
+    if last.ident == "Box" || last.ident == "Shared" {
+      if let PathArguments::AngleBracketed(args) = &last.arguments {
+        if let Some(GenericArgument::Type(inner)) = args.args.first() {
+          return unboxed_type(inner);
+        }
+      }
+    }
+  }
+  ty
+}
+
 fn is_primitive_type(ty: &Type) -> bool {
   match ty {
     Type::Path(ref type_path)
@@ -300,23 +562,25 @@ fn is_primitive_type(ty: &Type) -> bool {
       || type_path.path.is_ident("i128")
       || type_path.path.is_ident("f32")
       || type_path.path.is_ident("f64")
-      || type_path.path.is_ident("bool") => return true,
-    Type::Array(el_ty) => return is_primitive_type(&el_ty.elem),
+      || type_path.path.is_ident("bool") => true,
+    Type::Array(el_ty) => is_primitive_type(&el_ty.elem),
     Type::Tuple(tuple) => {
       for elem in &tuple.elems {
         if !is_primitive_type(elem) {
           return false;
         }
       }
-      return true;
+      true
     },
-    _ => return false,
+    _ => false,
   }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn create_vault_type_instance_for_struct(
     name: &Ident,
     new_name: Ident,
+    type_consts: TokenStream,
     make_struct: TokenStream,
     field_vars: &Vec<Ident>,
     field_types: Vec<Type>,
@@ -328,13 +592,18 @@ fn create_vault_type_instance_for_struct(
   )
     -> proc_macro::TokenStream {
 
+  let modified_members: Vec<&Member> = zip(field_members, is_modified_field)
+    .filter(|(_, is_modified)| **is_modified)
+    .map(|(field_member, _)| field_member)
+    .collect();
+
   let serialize_into_fields = zip(field_members, zip(is_modified_field, field_vars)).map(|(field_member, (is_modified, field_var))| {
     if *is_modified {
       quote! {
         let mut dest_nested = vec![];
-        self.#field_member.serialize_into(nested_dest, &mut dest_nested, type_map);
-        let #field_var = value_id_of(&dest_nested);
-        nested_dest.push((dest_nested, #field_var));
+        self.#field_member.serialize_into(nested_dest, &mut dest_nested, type_map)?;
+        let #field_var = type_map.value_id_of(&dest_nested);
+        nested_dest.push(dest_nested, #field_var);
       }
     } else {
       quote! {
@@ -349,12 +618,12 @@ fn create_vault_type_instance_for_struct(
       quote! {
         let mut dest = vec![];
         // Ignore the nested fields. We only care about the hash.
-        self.#field_member.serialize_into(&mut vec![], &mut dest, type_map);
-        result.extend(bincode::serde::encode_to_vec(value_id_of(&dest), BINCODE_CONFIG).unwrap());
+        self.#field_member.serialize_into(&mut NestedValues::new(), &mut dest, type_map)?;
+        result.extend(bincode::serde::encode_to_vec(type_map.value_id_of(&dest), BINCODE_CONFIG)?);
       }
     } else {
       quote! {
-        result.extend(bincode::serde::encode_to_vec(self.#field_member, BINCODE_CONFIG).unwrap());
+        result.extend(encode_key(&self.#field_member)?);
       }
     }
   });
@@ -363,14 +632,8 @@ fn create_vault_type_instance_for_struct(
   let deserialize_fields = zip(is_modified_field, zip(field_vars, zip(&field_types, field_members))).map(|(is_modified, (field_var, (field_type, field_member)))| {
     if *is_modified {
     quote! {
-      let nested_data = match lookup_id(new_struct.#field_member) {
-        None => {
-          eprintln!("Failed to look up ID {:?} for field {} of struct {}", new_struct.#field_member, stringify!(#field_member), stringify!(#name));
-          return None
-        },
-        Some(data) => data,
-      };
-      let #field_var : #field_type = deserialize_type::<#field_type>(&nested_data, lookup_id)?;
+      let nested_data = lookup_id(new_struct.#field_member)?.ok_or(VaultError::MissingId(new_struct.#field_member))?;
+      let #field_var : #field_type = deserialize_type::<#field_type>(&nested_data, type_map, lookup_id)?;
     }
   } else {
     quote! {
@@ -383,54 +646,57 @@ fn create_vault_type_instance_for_struct(
 
     impl VaultType for #name {
       type InnerVaultType = (#new_name #(,#modified_field_types)*);
+      type Fact = #new_name;
+      #type_consts
 
-      fn serialize_into(&self, nested_dest: &mut Vec<(Vec<u8>, ValueId)>, dest: &mut Vec<u8>, type_map: &TypeMap) {
+      fn serialize_into(&self, nested_dest: &mut NestedValues, dest: &mut Vec<u8>, type_map: &TypeMap) -> Result<(), VaultError> {
         #(
           #serialize_into_fields
         )*
         let strct = #assign_new_struct;
-        let mut serialized = match bincode::serde::encode_to_vec(&strct, BINCODE_CONFIG) {
-          Ok(vec) => vec,
-          Err(err) => panic!("bincode failed with: {:?}", err),
-        };
-        dest.append(&mut type_map.get(&TypeId::of::<Self>()).expect("Type not registered in type map").to_owned());
+        let mut serialized = bincode::serde::encode_to_vec(&strct, BINCODE_CONFIG)?;
+        dest.append(&mut type_map.tag_of::<Self>()?);
         dest.append(&mut serialized);
+        Ok(())
       }
 
-      fn serialize_prefix(&self, fields_in_prefix: u64, type_map: &TypeMap) -> Vec<u8> {
-        let mut result = type_map.get(&TypeId::of::<Self>()).expect("Type not registered in type map").to_owned();
+      fn serialize_prefix(&self, fields_in_prefix: u64, type_map: &TypeMap) -> Result<Vec<u8>, VaultError> {
+        let mut result = type_map.tag_of::<Self>()?;
         let mut remaining_fields = fields_in_prefix;
 
         #(
           if remaining_fields > 0 {
             #serialize_fields
           } else {
-            return result;
+            return Ok(result);
           }
           remaining_fields -= 1;
         )*
 
-        result
+        Ok(result)
       }
 
-      fn deserialize_value<'a>(data: &'a [u8], lookup_id: &dyn Fn(ValueId) -> Option<Vec<u8>>) -> Option<(&'a [u8],Self)> where Self: Sized {
+      fn deserialize_value<'a>(data: &'a [u8], type_map: &TypeMap, lookup_id: &dyn Fn(ValueId) -> Result<Option<Vec<u8>>, VaultError>) -> Result<(&'a [u8],Self), VaultError> where Self: Sized {
+        let fact = type_map.strip_tag::<Self>(data)?;
         let (new_struct, bytes_consumed): (#new_name, _) =
-          //TODO: Check that the type ID matches
-          match bincode::serde::decode_from_slice(&data[1..], BINCODE_CONFIG) {
-            Err(_) => {
-              eprintln!("Failed to decode struct of type {}, data: {:?}", stringify!(#new_name), &data);
-              return None
-            },
-            Ok((strct, bytes_consumed)) => (strct, bytes_consumed),
-        };
+          bincode::serde::decode_from_slice(fact, BINCODE_CONFIG)?;
         #(
           #deserialize_fields
         )*
-        Some((
-          &data[bytes_consumed..],
+        Ok((
+          &fact[bytes_consumed..],
           #build_struct
         ))
       }
+
+      #[allow(unused_mut, unused_variables)]
+      fn remap_fact_ids(data: &[u8], remap: &mut dyn FnMut(ValueId) -> Result<ValueId, VaultError>) -> Result<(Vec<u8>, usize), VaultError> {
+        let (mut fact, bytes_consumed): (#new_name, _) = bincode::serde::decode_from_slice(data, BINCODE_CONFIG)?;
+        #(
+          fact.#modified_members = remap(fact.#modified_members)?;
+        )*
+        Ok((bincode::serde::encode_to_vec(&fact, BINCODE_CONFIG)?, bytes_consumed))
+      }
     }
   }
   )
@@ -439,6 +705,11 @@ fn create_vault_type_instance_for_struct(
 struct NewFieldsInfo {
   field_members: Vec<Member>,
   field_types: Vec<Type>,
+  // The types of the fields in the `<Name>Fact`, where nested values are replaced by their ids.
+  new_field_types: Vec<Type>,
+  // Primitive fields are encoded so that their encodings sort like their values, see
+  // `ordered_key`.
+  new_field_attrs: Vec<TokenStream>,
   field_vars: Vec<Ident>,
   pattern_vars: Vec<Ident>,
   is_modified_field: Vec<bool>,
@@ -459,6 +730,7 @@ fn convert_named_fields(new_name: &Ident, named_fields: &FieldsNamed) -> NewFiel
   let mut unmodified_fields = Vec::new();
 
   let mut new_field_types = Vec::new();
+  let mut new_field_attrs = Vec::new();
 
   for field in &named_fields.named {
     let ty: &Type = &field.ty;
@@ -469,10 +741,12 @@ fn convert_named_fields(new_name: &Ident, named_fields: &FieldsNamed) -> NewFiel
     pattern_vars.push(syn::Ident::new(&format!("pat_{}", field.ident.as_ref().unwrap()), field.ident.as_ref().unwrap().span()));
     if is_primitive_type(ty) {
       new_field_types.push(ty.clone());
+      new_field_attrs.push(quote!(#[serde(with = "ordered_key")]));
       is_modified_field.push(false);
       unmodified_fields.push(field.ident.as_ref().unwrap().clone());
     } else {
       new_field_types.push(syn::parse_quote!(ValueId));
+      new_field_attrs.push(quote!());
       is_modified_field.push(true);
       modified_fields.push(field.ident.as_ref().unwrap().clone());
       modified_field_types.push(ty.clone());
@@ -481,7 +755,7 @@ fn convert_named_fields(new_name: &Ident, named_fields: &FieldsNamed) -> NewFiel
   let make_struct: TokenStream = quote!{
       #[derive(Serialize, Deserialize)]
       pub struct #new_name {
-        #(#field_members : #new_field_types),*
+        #(#new_field_attrs #field_members : #new_field_types),*
       }
   };
   let assign_new_struct: TokenStream = quote!{
@@ -497,6 +771,8 @@ fn convert_named_fields(new_name: &Ident, named_fields: &FieldsNamed) -> NewFiel
   NewFieldsInfo {
     field_members,
     field_types,
+    new_field_types,
+    new_field_attrs,
     field_vars,
     pattern_vars,
     is_modified_field,
@@ -518,6 +794,7 @@ fn convert_unnamed_fields(new_name: &Ident, unnamed_fields: &FieldsUnnamed) -> N
   let mut unmodified_field_indices = Vec::new();
 
   let mut new_field_types = Vec::new();
+  let mut new_field_attrs = Vec::new();
 
   for (i, field) in unnamed_fields.unnamed.iter().enumerate() {
     let ty: &Type = &field.ty;
@@ -530,10 +807,12 @@ fn convert_unnamed_fields(new_name: &Ident, unnamed_fields: &FieldsUnnamed) -> N
     pattern_vars.push(syn::Ident::new(&format!("pat_{}", i), proc_macro2::Span::call_site()));
     if is_primitive_type(ty) {
       new_field_types.push(ty.clone());
+      new_field_attrs.push(quote!(#[serde(with = "ordered_key")]));
       is_modified_field.push(false);
       unmodified_field_indices.push(index);
     } else {
       new_field_types.push(syn::parse_quote!(ValueId));
+      new_field_attrs.push(quote!());
       is_modified_field.push(true);
       modified_field_types.push(ty.clone());
       modified_field_indices.push(index);
@@ -542,7 +821,7 @@ fn convert_unnamed_fields(new_name: &Ident, unnamed_fields: &FieldsUnnamed) -> N
   let make_struct = quote!{
     #[derive(Serialize, Deserialize)]
     pub struct #new_name (
-      #(#field_types),*
+      #(#new_field_attrs #new_field_types),*
     );
   };
   let assign_new_struct = quote!{
@@ -558,6 +837,8 @@ fn convert_unnamed_fields(new_name: &Ident, unnamed_fields: &FieldsUnnamed) -> N
   NewFieldsInfo {
     field_members,
     field_types,
+    new_field_types,
+    new_field_attrs,
     field_vars,
     pattern_vars,
     is_modified_field,
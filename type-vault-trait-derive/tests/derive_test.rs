@@ -1,12 +1,9 @@
 use type_vault_trait::*;
 use type_vault_trait_derive::VaultType;
 
-use std::any::TypeId;
-
 use serde::{Deserialize, Serialize};
-use bincode;
 
-#[derive(Hash, Clone, Deserialize, VaultType)]
+#[derive(Hash, Clone, Deserialize, VaultType, Debug, PartialEq)]
 struct BaseStruct {
     foo: i32,
 }
@@ -38,12 +35,27 @@ enum TestUnnamedEnum {
     C(),
 }
 
+#[derive(VaultType)]
+struct HolderStruct {
+    inner: BaseStruct,
+}
+
 #[derive(Deserialize, VaultType, Debug, PartialEq)]
 enum TestNamedEnum {
     A { x: i32 },
     B { y: f64, z: bool },
 }
 
+#[derive(VaultType, Debug, PartialEq)]
+struct NestedUnnamedStruct(BaseStruct, i32);
+
+#[derive(VaultType, Debug, PartialEq)]
+enum NestedEnum {
+    Leaf(i32),
+    Pair(BaseStruct, Box<NestedEnum>),
+    Named { inner: BaseStruct, n: u8 },
+}
+
 #[test]
 fn test_derive() {
     let test_struct =
@@ -60,28 +72,28 @@ fn test_derive() {
             named_enum_field: TestNamedEnum::A { x: 123  },
         };
     let id_map = TypeMap::new(vec![
-        TypeId::of::<TestStruct>(),
-        TypeId::of::<BaseStruct>(),
-        TypeId::of::<UnitStruct>(),
-        TypeId::of::<UnnamedStruct>(),
-        TypeId::of::<TestUnnamedEnum>(),
-        TypeId::of::<TestNamedEnum>(),]);
-    let serialized: Vec<(Vec<u8>, ValueId)> = serialize_type(&test_struct, &id_map);
+        TypeInfo::of::<TestStruct>(),
+        TypeInfo::of::<BaseStruct>(),
+        TypeInfo::of::<UnitStruct>(),
+        TypeInfo::of::<UnnamedStruct>(),
+        TypeInfo::of::<TestUnnamedEnum>(),
+        TypeInfo::of::<TestNamedEnum>(),]);
+    let serialized: Vec<(Vec<u8>, ValueId)> = serialize_type(&test_struct, &id_map).unwrap();
     println!("Serialized: {:?}", serialized);
     assert_eq!(serialized.len(), 6); // One for the struct itself, one for each of the nested structs
 
     // Test prefix serialization
-    assert_eq!(test_struct.serialize_prefix(10, &id_map), serialize_type(&test_struct, &id_map).pop().unwrap().0);
-    println!("Prefix with all fields: {:?}", test_struct.serialize_prefix(10, &id_map));
+    assert_eq!(test_struct.serialize_prefix(10, &id_map).unwrap(), serialize_type(&test_struct, &id_map).unwrap().pop().unwrap().0);
+    println!("Prefix with all fields: {:?}", test_struct.serialize_prefix(10, &id_map).unwrap());
 
     // Deserialization test
     let table = serialized.iter().cloned().map(|(val,id)| (id,val))
             .collect::<std::collections::HashMap<ValueId, Vec<u8>>>();
     let lookup_id = |value_id| {
-        table.get(&value_id).cloned()
+        Ok(table.get(&value_id).cloned())
     };
-    match TestStruct::deserialize_value(&serialized[serialized.len()-1].0, &lookup_id) {
-    Some((_,deserialized)) => {
+    match TestStruct::deserialize_value(&serialized[serialized.len()-1].0, &id_map, &lookup_id) {
+    Ok((_,deserialized)) => {
             // Check that the deserialized instance matches the original
             assert_eq!(deserialized.i32_field, test_struct.i32_field);
             assert_eq!(deserialized.f64_field, test_struct.f64_field);
@@ -92,6 +104,174 @@ fn test_derive() {
             assert_eq!(deserialized.unnamed_enum_struct_field, test_struct.unnamed_enum_struct_field);
             assert_eq!(deserialized.named_enum_field, test_struct.named_enum_field);
     },
-        None => panic!("Deserialization failed"),
+        Err(err) => panic!("Deserialization failed: {}", err),
+    }
+}
+
+#[test]
+fn test_deserialize_errors() {
+    let id_map = TypeMap::new(vec![TypeInfo::of::<TestStruct>(), TypeInfo::of::<BaseStruct>()]);
+    let no_lookup = |_| Ok(None);
+
+    // Empty input is reported instead of panicking.
+    assert!(matches!(BaseStruct::deserialize_value(&[], &id_map, &no_lookup), Err(VaultError::Truncated(_))));
+    assert!(matches!(UnitStruct::deserialize_value(&[], &id_map, &no_lookup), Err(VaultError::Truncated(_))));
+    assert!(matches!(Option::<BaseStruct>::deserialize_value(&[7], &id_map, &no_lookup), Err(VaultError::Corrupt(_))));
+    // As are truncated and malformed tags and facts.
+    assert!(matches!(BaseStruct::deserialize_value(&[251, 1], &id_map, &no_lookup), Err(VaultError::Decode(_))));
+    assert!(matches!(BaseStruct::deserialize_value(&[3], &id_map, &no_lookup), Err(VaultError::Decode(_))));
+    assert!(matches!(BaseStruct::deserialize_value(&[255, 255], &id_map, &no_lookup), Err(VaultError::Decode(_))));
+
+    // Values are only decoded as the type they were stored as.
+    let base = serialize_type(&BaseStruct { foo: 1 }, &id_map).unwrap().pop().unwrap().0;
+    match TestStruct::deserialize_value(&base, &id_map, &no_lookup) {
+        Err(VaultError::WrongType { expected, found }) => assert_eq!((expected, found), ("TestStruct", "BaseStruct")),
+        _ => panic!("Expected a wrong type error"),
     }
+    assert!(matches!(BaseStruct::deserialize_value(&[9, 2], &id_map, &no_lookup), Err(VaultError::UnknownTypeTag(tag)) if tag == vec![9]));
+
+    // Nested values that are not in the store are reported as missing.
+    let holder = HolderStruct { inner: BaseStruct { foo: 1 } };
+    let holder_map = TypeMap::new(vec![TypeInfo::of::<HolderStruct>(), TypeInfo::of::<BaseStruct>()]);
+    let serialized = serialize_type(&holder, &holder_map).unwrap();
+    let nested_id = serialized[0].1;
+    match HolderStruct::deserialize_value(&serialized[1].0, &holder_map, &no_lookup) {
+        Err(VaultError::MissingId(id)) => assert_eq!(id, nested_id),
+        _ => panic!("Expected a missing id error"),
+    }
+
+    // Serializing a type that was never registered is an error, not a panic.
+    assert!(matches!(serialize_type(&UnitStruct, &id_map), Err(VaultError::UnregisteredType(_))));
+}
+
+#[test]
+fn test_multi_byte_tags() {
+    let mut id_map = TypeMap::new(vec![]);
+    id_map.insert(TypeInfo::of::<HolderStruct>(), 300);
+    id_map.insert(TypeInfo::of::<BaseStruct>(), 70000);
+    let holder = HolderStruct { inner: BaseStruct { foo: 1 } };
+    let serialized = serialize_type(&holder, &id_map).unwrap();
+    assert_eq!(decode_tag(&serialized[1].0).unwrap(), (300, 3));
+    assert_eq!(decode_tag(&serialized[0].0).unwrap(), (70000, 5));
+    let lookup_id = |value_id| Ok(serialized.iter().find(|(_, id)| *id == value_id).map(|(val, _)| val.clone()));
+    let decoded = deserialize_type::<HolderStruct>(&serialized[1].0, &id_map, &lookup_id).unwrap();
+    assert_eq!(decoded.inner, holder.inner);
+    match deserialize_type::<HolderStruct>(&serialized[0].0, &id_map, &lookup_id) {
+        Err(VaultError::WrongType { expected, found }) => assert_eq!((expected, found), ("HolderStruct", "BaseStruct")),
+        _ => panic!("Expected a wrong type error"),
+    }
+    // A multi-byte tag cut short.
+    assert!(deserialize_type::<HolderStruct>(&serialized[1].0[..2], &id_map, &lookup_id).is_err());
+}
+
+// Ids are persisted, so they must not change between compiler releases or platforms. These are
+// the ids the current encoding and `Blake3Hasher` give to a few known values.
+#[test]
+fn test_golden_ids() {
+    let id_map = TypeMap::with_hasher(vec![
+        TypeInfo::of::<TestStruct>(),
+        TypeInfo::of::<BaseStruct>(),
+        TypeInfo::of::<UnitStruct>(),
+        TypeInfo::of::<UnnamedStruct>(),
+        TypeInfo::of::<TestUnnamedEnum>(),
+        TypeInfo::of::<TestNamedEnum>(),], Box::new(Blake3Hasher), 8);
+    let mut empty_hash = [0u8; 8];
+    Blake3Hasher.hash_into(b"", &mut empty_hash);
+    assert_eq!(empty_hash, [0xaf, 0x13, 0x49, 0xb9, 0xf5, 0xf9, 0xa1, 0xa6]);
+    let root_id = |serialized: Vec<(Vec<u8>, ValueId)>| serialized.last().unwrap().1.to_string();
+    assert_eq!(root_id(serialize_type(&BaseStruct { foo: 10 }, &id_map).unwrap()), "e61f7fe38735ef54");
+    assert_eq!(root_id(serialize_type(&UnitStruct, &id_map).unwrap()), "0c389a743e34fda4");
+    assert_eq!(root_id(serialize_type(&TestNamedEnum::B { y: 1.5, z: true }, &id_map).unwrap()), "38ead49377ecb2c7");
+    let test_struct =
+        TestStruct {
+            i32_field: -42,
+            f64_field: 0.1,
+            bool_field: true,
+            unit_field: (),
+            tuple_field: (1, 0.2, false, ()),
+            base_field: BaseStruct { foo: 10 },
+            unit_struct_field: UnitStruct,
+            unnamed_struct_field: UnnamedStruct(7),
+            unnamed_enum_struct_field: TestUnnamedEnum::A(99),
+            named_enum_field: TestNamedEnum::A { x: 123  },
+        };
+    assert_eq!(root_id(serialize_type(&test_struct, &id_map).unwrap()), "adaf647fe58cfa95");
+
+    // Wider ids extend the same hash.
+    let wide_map = |width| TypeMap::with_hasher(vec![TypeInfo::of::<BaseStruct>()], Box::new(Blake3Hasher), width);
+    assert_eq!(root_id(serialize_type(&BaseStruct { foo: 10 }, &wide_map(16)).unwrap()), "7588d3f0c788a455d9c735535f0c3f78");
+    assert_eq!(root_id(serialize_type(&BaseStruct { foo: 10 }, &wide_map(32)).unwrap()), "7588d3f0c788a455d9c735535f0c3f7804f9a96f179d5e8b69894689f3fea861");
+}
+
+#[test]
+fn test_type_names_and_schemas() {
+    let field = |name: &str, ty: &str, storage| FieldSchema { name: name.to_owned(), ty: ty.to_owned(), storage, indexed: false };
+    assert_eq!(BaseStruct::TYPE_NAME, "BaseStruct");
+    assert_eq!(BaseStruct::schema(), Schema::Struct(vec![field("foo", "i32", FieldStorage::Inline)]));
+    assert_eq!(UnitStruct::schema(), Schema::Struct(vec![]));
+    assert_eq!(UnnamedStruct::schema(), Schema::Struct(vec![field("0", "i32", FieldStorage::Inline)]));
+    assert_eq!(HolderStruct::schema(), Schema::Struct(vec![field("inner", "BaseStruct", FieldStorage::ById)]));
+    assert_eq!(TestNamedEnum::schema(), Schema::Enum(vec![
+        VariantSchema { name: "A".to_owned(), fields: vec![field("x", "i32", FieldStorage::Inline)] },
+        VariantSchema { name: "B".to_owned(), fields: vec![
+            field("y", "f64", FieldStorage::Inline),
+            field("z", "bool", FieldStorage::Inline),
+        ] },
+    ]));
+    match TestStruct::schema() {
+        Schema::Struct(fields) => {
+            assert_eq!(fields[4], field("tuple_field", "(i32,f64,bool,())", FieldStorage::Inline));
+            assert_eq!(fields[9], field("named_enum_field", "TestNamedEnum", FieldStorage::ById));
+        },
+        Schema::Enum(_) => panic!("TestStruct is a struct"),
+    }
+}
+
+#[test]
+fn test_nested_fields() {
+    let id_map = TypeMap::new(vec![
+        TypeInfo::of::<BaseStruct>(),
+        TypeInfo::of::<NestedUnnamedStruct>(),
+        TypeInfo::of::<NestedEnum>(),]);
+    let value = NestedEnum::Pair(BaseStruct { foo: 1 }, Box::new(NestedEnum::Named { inner: BaseStruct { foo: 2 }, n: 3 }));
+    let serialized = serialize_type(&value, &id_map).unwrap();
+    let table = serialized.iter().cloned().map(|(val,id)| (id,val))
+            .collect::<std::collections::HashMap<ValueId, Vec<u8>>>();
+    let lookup_id = |value_id| Ok(table.get(&value_id).cloned());
+    let (root, _) = serialized.last().unwrap();
+    assert_eq!(deserialize_type::<NestedEnum>(root, &id_map, &lookup_id).unwrap(), value);
+
+    let unnamed = NestedUnnamedStruct(BaseStruct { foo: 4 }, 5);
+    let serialized_unnamed = serialize_type(&unnamed, &id_map).unwrap();
+    assert_eq!(serialized_unnamed.len(), 2);
+    let lookup_unnamed = |value_id| Ok(serialized_unnamed.iter().find(|(_, id)| *id == value_id).map(|(val, _)| val.clone()));
+    assert_eq!(deserialize_type::<NestedUnnamedStruct>(&serialized_unnamed[1].0, &id_map, &lookup_unnamed).unwrap(), unnamed);
+
+    // Remapping visits the ids of the nested values, and leaves the fact alone if they stay.
+    let (_, tag_len) = decode_tag(root).unwrap();
+    let mut visited = vec![];
+    let (fact, bytes_consumed) = NestedEnum::remap_fact_ids(&root[tag_len..], &mut |id| {
+        visited.push(id);
+        Ok(id)
+    }).unwrap();
+    assert_eq!(fact, root[tag_len..]);
+    assert_eq!(bytes_consumed, root.len() - tag_len);
+    let nested_ids: Vec<ValueId> = serialized.iter().map(|(_, id)| *id).collect();
+    assert_eq!(visited, vec![nested_ids[0], nested_ids[2]]);
+    let (remapped, _) = NestedEnum::remap_fact_ids(&root[tag_len..], &mut |_| Ok(nested_ids[0])).unwrap();
+    assert_ne!(remapped, fact);
+
+    assert_eq!(NestedEnum::schema(), Schema::Enum(vec![
+        VariantSchema { name: "Leaf".to_owned(), fields: vec![
+            FieldSchema { name: "0".to_owned(), ty: "i32".to_owned(), storage: FieldStorage::Inline, indexed: false },
+        ] },
+        VariantSchema { name: "Pair".to_owned(), fields: vec![
+            FieldSchema { name: "0".to_owned(), ty: "BaseStruct".to_owned(), storage: FieldStorage::ById, indexed: false },
+            FieldSchema { name: "1".to_owned(), ty: "NestedEnum".to_owned(), storage: FieldStorage::ById, indexed: false },
+        ] },
+        VariantSchema { name: "Named".to_owned(), fields: vec![
+            FieldSchema { name: "inner".to_owned(), ty: "BaseStruct".to_owned(), storage: FieldStorage::ById, indexed: false },
+            FieldSchema { name: "n".to_owned(), ty: "u8".to_owned(), storage: FieldStorage::Inline, indexed: false },
+        ] },
+    ]));
 }
@@ -1,155 +1,772 @@
-use bincode;
-use std::{any::TypeId, collections::HashMap, hash::*};
+use std::{any::TypeId, collections::HashMap, fmt, iter::zip, ops::Deref, sync::{atomic::{AtomicU64, Ordering}, Arc, OnceLock}};
 
-pub type ValueId = [u8; 8];
+mod schema;
+pub use schema::*;
+pub mod ordered_key;
+pub use ordered_key::{encode_key, OrderedKey};
 
-pub fn value_id_of(data: impl Hash) -> ValueId {
-    let mut s = std::collections::hash_map::DefaultHasher::new();
-    data.hash(&mut s);
-    s.finish().to_be_bytes()
+/// The widest id a vault can be configured with, in bytes.
+pub const MAX_ID_WIDTH: usize = 32;
+
+/// The id width used unless a vault is configured otherwise, in bytes.
+pub const DEFAULT_ID_WIDTH: usize = 16;
+
+/// The content-derived id of a stored value. How many bytes wide it is gets chosen when a vault
+/// is created, up to `MAX_ID_WIDTH`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ValueId {
+    len: u8,
+    bytes: [u8; MAX_ID_WIDTH],
+}
+
+impl ValueId {
+    /// Returns `None` if `bytes` is empty or longer than `MAX_ID_WIDTH`.
+    pub fn from_slice(bytes: &[u8]) -> Option<ValueId> {
+        if bytes.is_empty() || bytes.len() > MAX_ID_WIDTH {
+            return None;
+        }
+        let mut id = ValueId { len: bytes.len() as u8, bytes: [0; MAX_ID_WIDTH] };
+        id.bytes[..bytes.len()].copy_from_slice(bytes);
+        Some(id)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub fn width(&self) -> usize {
+        self.len as usize
+    }
+}
+
+impl AsRef<[u8]> for ValueId {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
 }
 
-pub struct TypeMap (HashMap<TypeId,usize>);
+impl fmt::Display for ValueId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ValueId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ValueId({})", self)
+    }
+}
+
+impl serde::Serialize for ValueId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.as_bytes())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ValueId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct IdVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for IdVisitor {
+            type Value = ValueId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "between 1 and {} id bytes", MAX_ID_WIDTH)
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<ValueId, E> {
+                ValueId::from_slice(bytes).ok_or_else(|| E::invalid_length(bytes.len(), &self))
+            }
+        }
+
+        deserializer.deserialize_bytes(IdVisitor)
+    }
+}
+
+/// Everything that can go wrong when storing values in or reading them back from a vault.
+#[derive(Debug)]
+pub enum VaultError {
+    /// The underlying storage engine failed.
+    Storage(Box<dyn std::error::Error + Send + Sync>),
+    /// A value of a type that was never registered with the `TypeMap` was serialized.
+    UnregisteredType(&'static str),
+    /// Stored data starts with a type tag that no registered type uses.
+    UnknownTypeTag(Vec<u8>),
+    /// A value of one registered type was decoded as a value of another.
+    WrongType { expected: &'static str, found: &'static str },
+    /// A stored value refers to an id that is not present in the vault.
+    MissingId(ValueId),
+    /// The data ended before a complete value of the given type could be read.
+    Truncated(&'static str),
+    /// The data is not a valid encoding of any value.
+    Corrupt(String),
+    /// The operation is not supported for the given type.
+    Unsupported(&'static str),
+    /// The type has no field of that name which is stored inline with the given type.
+    UnknownField { type_name: &'static str, field: String, ty: &'static str },
+    /// A rule can't be evaluated, for the given reason.
+    InvalidRule(String),
+    /// The vault was created with a different `ContentHasher` than the one it is opened with.
+    HasherMismatch { stored: String, configured: &'static str },
+    /// The vault was created with a different id width than the one it is opened with.
+    IdWidthMismatch { stored: usize, configured: usize },
+    /// Two different values hash to the same id. Storing the second one would overwrite the first.
+    IdCollision(ValueId),
+    /// The value can't be removed, as other stored values or roots still refer to it.
+    StillReferenced(ValueId),
+    /// The vault was opened read-only, so nothing can be written to it.
+    ReadOnly,
+    /// More than one registered type uses the same type name.
+    DuplicateTypeName(&'static str),
+    /// The definitions of these registered types no longer match the schemas their values were
+    /// stored with.
+    SchemaMismatch(Vec<SchemaMismatch>),
+    Encode(bincode::error::EncodeError),
+    Decode(bincode::error::DecodeError),
+}
+
+impl VaultError {
+    pub fn storage(err: impl std::error::Error + Send + Sync + 'static) -> Self {
+        VaultError::Storage(Box::new(err))
+    }
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VaultError::Storage(err) => write!(f, "storage error: {}", err),
+            VaultError::UnregisteredType(name) => write!(f, "type {} is not registered in the type map", name),
+            VaultError::UnknownTypeTag(tag) => write!(f, "unknown type tag {:?}", tag),
+            VaultError::WrongType { expected, found } =>
+                write!(f, "expected a value of type {} but found a value of type {}", expected, found),
+            VaultError::MissingId(id) => write!(f, "no value stored with id {}", id),
+            VaultError::Truncated(name) => write!(f, "data ended while decoding a value of type {}", name),
+            VaultError::Corrupt(msg) => write!(f, "corrupt data: {}", msg),
+            VaultError::Unsupported(msg) => write!(f, "unsupported operation: {}", msg),
+            VaultError::UnknownField { type_name, field, ty } =>
+                write!(f, "type {} has no inline field {} of type {}", type_name, field, ty),
+            VaultError::InvalidRule(msg) => write!(f, "invalid rule: {}", msg),
+            VaultError::HasherMismatch { stored, configured } =>
+                write!(f, "vault was created with hasher {} but is opened with {}", stored, configured),
+            VaultError::IdWidthMismatch { stored, configured } =>
+                write!(f, "vault was created with {} byte ids but is opened with {} byte ids", stored, configured),
+            VaultError::IdCollision(id) => write!(f, "two different values have the id {}", id),
+            VaultError::StillReferenced(id) => write!(f, "value {} is still referred to by other values or roots", id),
+            VaultError::ReadOnly => write!(f, "vault was opened read-only"),
+            VaultError::DuplicateTypeName(name) => write!(f, "more than one type is registered as {}", name),
+            VaultError::SchemaMismatch(mismatches) => {
+                write!(f, "types changed incompatibly since their values were stored:")?;
+                for mismatch in mismatches {
+                    write!(f, " {};", mismatch)?;
+                }
+                Ok(())
+            },
+            VaultError::Encode(err) => write!(f, "bincode failed to encode: {}", err),
+            VaultError::Decode(err) => write!(f, "bincode failed to decode: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for VaultError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VaultError::Storage(err) => Some(err.as_ref()),
+            VaultError::Encode(err) => Some(err),
+            VaultError::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<bincode::error::EncodeError> for VaultError {
+    fn from(err: bincode::error::EncodeError) -> Self {
+        VaultError::Encode(err)
+    }
+}
+
+impl From<bincode::error::DecodeError> for VaultError {
+    fn from(err: bincode::error::DecodeError) -> Self {
+        VaultError::Decode(err)
+    }
+}
+
+/// The hash function that derives the `ValueId` of a value from its serialized bytes.
+///
+/// Ids are persisted, so an implementation must produce the same output for the same input on
+/// every platform and in every release. A vault records the `name` of the hasher it was created
+/// with and refuses to be opened with a different one.
+pub trait ContentHasher: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Fills all of `out`, which is as long as the vault's id width, with the hash of `data`.
+    fn hash_into(&self, data: &[u8], out: &mut [u8]);
+}
+
+/// The default hasher: the BLAKE3 hash of the serialized value, truncated to the id width.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Blake3Hasher;
+
+impl ContentHasher for Blake3Hasher {
+    fn name(&self) -> &'static str {
+        "blake3"
+    }
+
+    fn hash_into(&self, data: &[u8], out: &mut [u8]) {
+        blake3::Hasher::new().update(data).finalize_xof().fill(out);
+    }
+}
+
+/// The smallest tag given to a registered type. Values nested in an `Option` are stored behind a
+/// 0 or 1 byte, so tags start above those to keep such values apart from tagged values.
+pub const FIRST_TYPE_TAG: u64 = 2;
+
+/// Rewrites the ids in a stored fact, see `VaultType::remap_fact_ids`.
+pub type RemapFactIds =
+    fn(&[u8], &mut dyn FnMut(ValueId) -> Result<ValueId, VaultError>) -> Result<(Vec<u8>, usize), VaultError>;
+
+/// Encodes the fields of a stored fact, see `VaultType::encoded_fields`.
+pub type EncodedFields = fn(&[u8]) -> Result<Vec<Vec<u8>>, VaultError>;
+
+/// Encodes the indexed fields of a stored fact, see `VaultType::indexed_fields`.
+pub type IndexedFields = fn(&[u8]) -> Result<Vec<(u64, Vec<u8>)>, VaultError>;
+
+/// What a vault needs to know about a type to register it.
+#[derive(Clone, Debug)]
+pub struct TypeInfo {
+    pub type_id: TypeId,
+    pub name: &'static str,
+    pub schema: Schema,
+    pub remap_fact_ids: RemapFactIds,
+    pub encoded_fields: EncodedFields,
+    pub indexed_fields: IndexedFields,
+}
+
+impl TypeInfo {
+    pub fn of<T: VaultType + 'static>() -> Self {
+        TypeInfo {
+            type_id: TypeId::of::<T>(),
+            name: T::TYPE_NAME,
+            schema: T::schema(),
+            remap_fact_ids: T::remap_fact_ids,
+            encoded_fields: T::encoded_fields,
+            indexed_fields: T::indexed_fields,
+        }
+    }
+}
+
+pub struct TypeMap {
+    tags: HashMap<TypeId,u64>,
+    types: HashMap<u64, TypeInfo>,
+    hasher: Box<dyn ContentHasher>,
+    id_width: usize,
+    // Tells this map apart from every other one in the process, as it was last changed. Values
+    // serialize differently with different tags, hashers or id widths, so `Shared` remembers it
+    // with a serialization.
+    generation: u64,
+}
+
+static TYPE_MAP_GENERATIONS: AtomicU64 = AtomicU64::new(0);
 
 impl TypeMap {
-    pub fn new(type_ids: Vec<TypeId>) -> Self {
-        let mut map = HashMap::new();
-        for (i, type_id) in type_ids.into_iter().enumerate() {
-            map.insert(type_id, i);
+    /// Tags the types in the order they are given. Vaults instead keep the tags they hand out
+    /// in the database and `insert` the types with those.
+    pub fn new(types: Vec<TypeInfo>) -> Self {
+        TypeMap::with_hasher(types, Box::new(Blake3Hasher), DEFAULT_ID_WIDTH)
+    }
+
+    /// Panics if `id_width` is not between 1 and `MAX_ID_WIDTH`.
+    pub fn with_hasher(types: Vec<TypeInfo>, hasher: Box<dyn ContentHasher>, id_width: usize) -> Self {
+        assert!((1..=MAX_ID_WIDTH).contains(&id_width), "id width {} out of range", id_width);
+        let generation = TYPE_MAP_GENERATIONS.fetch_add(1, Ordering::Relaxed);
+        let mut type_map = TypeMap { tags: HashMap::new(), types: HashMap::new(), hasher, id_width, generation };
+        for (info, tag) in zip(types, FIRST_TYPE_TAG..) {
+            type_map.insert(info, tag);
         }
-        TypeMap(map)
+        type_map
+    }
+
+    pub fn insert(&mut self, info: TypeInfo, tag: u64) {
+        self.tags.insert(info.type_id, tag);
+        self.types.insert(tag, info);
+        self.generation = TYPE_MAP_GENERATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Like `tag_of`, but returns everything the map knows about the type.
+    pub fn info_of<T: ?Sized + 'static>(&self) -> Result<&TypeInfo, VaultError> {
+        self.tags.get(&TypeId::of::<T>()).and_then(|tag| self.types.get(tag))
+            .ok_or(VaultError::UnregisteredType(std::any::type_name::<T>()))
+    }
+
+    /// The type that values tagged with `tag` belong to.
+    pub fn type_of_tag(&self, tag: u64) -> Option<&TypeInfo> {
+        self.types.get(&tag)
     }
 
     pub fn get(&self, type_id: &TypeId) -> Option<Vec<u8>> {
-        self.0.get(type_id).copied()
+        self.tags.get(type_id).copied()
         .map(|u| bincode::serde::encode_to_vec(u, BINCODE_CONFIG).ok())?
     }
+
+    /// Like `get`, but reports an unregistered type as an error.
+    pub fn tag_of<T: ?Sized + 'static>(&self) -> Result<Vec<u8>, VaultError> {
+        self.get(&TypeId::of::<T>())
+            .ok_or(VaultError::UnregisteredType(std::any::type_name::<T>()))
+    }
+
+    /// Checks that `data` starts with the tag of `T` and returns the fact that follows it.
+    pub fn strip_tag<'a, T: VaultType + 'static>(&self, data: &'a [u8]) -> Result<&'a [u8], VaultError> {
+        if data.is_empty() {
+            return Err(VaultError::Truncated(T::TYPE_NAME));
+        }
+        let expected = *self.tags.get(&TypeId::of::<T>())
+            .ok_or(VaultError::UnregisteredType(std::any::type_name::<T>()))?;
+        let (tag, tag_len) = decode_tag(data)?;
+        if tag != expected {
+            return Err(match self.types.get(&tag) {
+                Some(info) => VaultError::WrongType { expected: T::TYPE_NAME, found: info.name },
+                None => VaultError::UnknownTypeTag(data[..tag_len].to_vec()),
+            });
+        }
+        Ok(&data[tag_len..])
+    }
+
+    pub fn hasher(&self) -> &dyn ContentHasher {
+        self.hasher.as_ref()
+    }
+
+    pub fn id_width(&self) -> usize {
+        self.id_width
+    }
+
+    pub fn value_id_of(&self, data: &[u8]) -> ValueId {
+        let mut id = ValueId { len: self.id_width as u8, bytes: [0; MAX_ID_WIDTH] };
+        self.hasher.hash_into(data, &mut id.bytes[..self.id_width]);
+        id
+    }
 }
 
 pub trait VaultType {
     type InnerVaultType;
-    // The reason for multiple Vec<u8> here is to allow for nested structs.
-    // The serialized Vec<u8> are in post order, meaning that the nested structs
-    // come first and the toplevel structs comes last.
-    fn serialize_into(&self, nested_dest: &mut Vec<(Vec<u8>, ValueId)>, dest: &mut Vec<u8>, type_map: &TypeMap);
-    fn serialize_prefix(&self, fields_in_prefix: u64, type_map: &TypeMap) -> Vec<u8>;
-    fn deserialize_value<'a>(data: &'a [u8], lookup_id: &dyn Fn (ValueId) -> Option<Vec<u8>>) -> Option<(&'a [u8],Self)> where Self: Sized;
+    /// What values of the type are stored as, which for derived types is the generated
+    /// `<Name>Fact` with nested values replaced by their ids.
+    type Fact;
+    /// The name the type is registered under in a vault. Unlike its `TypeId` it stays the same
+    /// across compilations, so the tags of stored values are looked up by it.
+    const TYPE_NAME: &'static str;
+    /// Describes how values of the type are stored. A vault refuses to open when the schema of a
+    /// registered type is incompatible with the one its values were stored with.
+    fn schema() -> Schema where Self: Sized;
+    /// How the type shows up in the schemas of types that store it by id. Since only the id is
+    /// stored, this is the name of the stored type, which wrappers such as `Box` don't change.
+    fn stored_type_name() -> String where Self: Sized;
+    // The reason for `nested_dest` is to allow for nested structs. They are pushed to it in post
+    // order, meaning that the nested structs come first and the toplevel structs comes last.
+    fn serialize_into(&self, nested_dest: &mut NestedValues, dest: &mut Vec<u8>, type_map: &TypeMap) -> Result<(), VaultError>;
+    fn serialize_prefix(&self, fields_in_prefix: u64, type_map: &TypeMap) -> Result<Vec<u8>, VaultError>;
+    // The type tags in `data` are checked against `type_map`, so a value is never decoded as
+    // another type. `lookup_id` returns `Ok(None)` when the id is not stored; implementations
+    // report that as `VaultError::MissingId`.
+    fn deserialize_value<'a>(data: &'a [u8], type_map: &TypeMap, lookup_id: &dyn Fn (ValueId) -> Result<Option<Vec<u8>>, VaultError>) -> Result<(&'a [u8],Self), VaultError> where Self: Sized;
+    // Decodes the fact at the start of `data`, which is what follows the type tag of a stored
+    // value, replaces every id in it with `remap(id)` and returns the re-encoded fact together
+    // with the number of bytes of `data` it took up.
+    fn remap_fact_ids(data: &[u8], remap: &mut dyn FnMut(ValueId) -> Result<ValueId, VaultError>) -> Result<(Vec<u8>, usize), VaultError> where Self: Sized;
+    // The encoding of each field of the fact at the start of `fact`, in declaration order, the
+    // same as in the fact itself. Enums have no fields of their own.
+    fn encoded_fields(fact: &[u8]) -> Result<Vec<Vec<u8>>, VaultError> where Self: Sized;
+    // The position and encoding of every field marked `#[vault(index)]` in the fact at the start
+    // of `fact`.
+    fn indexed_fields(fact: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, VaultError> where Self: Sized;
+}
+
+/// A query that constrains any subset of the fields of `Target`, generated as `<Name>Query` by
+/// `#[derive(VaultType)]` for structs with named fields.
+pub trait VaultQuery {
+    type Target: VaultType;
+    /// The constraint on each field, in declaration order.
+    fn field_constraints(&self, type_map: &TypeMap) -> Result<Vec<FieldConstraint<'_>>, VaultError>;
+}
+
+/// What a query requires of one field of the values it matches.
+pub enum FieldConstraint<'q> {
+    /// The field may have any value.
+    Any,
+    /// The field must have this encoding, which is the same as in the stored facts.
+    Equals(Vec<u8>),
+    /// The field is stored by id and must refer to a value that matches the query.
+    Matching(&'q dyn NestedQuery),
+}
+
+/// A query on the values that a field refers to, see `FieldConstraint::Matching`. Every
+/// `VaultQuery` is one, which lets vaults run it without knowing the type it looks for.
+pub trait NestedQuery {
+    /// The type of the values the query looks for.
+    fn target<'m>(&self, type_map: &'m TypeMap) -> Result<&'m TypeInfo, VaultError>;
+    fn constraints(&self, type_map: &TypeMap) -> Result<Vec<FieldConstraint<'_>>, VaultError>;
+}
+
+impl<Q: VaultQuery> NestedQuery for Q where Q::Target: 'static {
+    fn target<'m>(&self, type_map: &'m TypeMap) -> Result<&'m TypeInfo, VaultError> {
+        type_map.info_of::<Q::Target>()
+    }
+
+    fn constraints(&self, type_map: &TypeMap) -> Result<Vec<FieldConstraint<'_>>, VaultError> {
+        self.field_constraints(type_map)
+    }
+}
+
+/// Constrains a field that is stored by id, either to the id of a value, to the id itself, or to
+/// the ids of the values that match a query.
+pub enum ByIdConstraint<'q, T> {
+    Value(&'q T),
+    Id(ValueId),
+    Matching(Box<dyn NestedQuery + 'q>),
+}
+
+impl<T: VaultType> ByIdConstraint<'_, T> {
+    pub fn constraint(&self, type_map: &TypeMap) -> Result<FieldConstraint<'_>, VaultError> {
+        let id = match self {
+            ByIdConstraint::Value(value) => {
+                let mut dest = vec![];
+                // Ignore the nested values. We only care about the hash.
+                value.serialize_into(&mut NestedValues::new(), &mut dest, type_map)?;
+                type_map.value_id_of(&dest)
+            },
+            ByIdConstraint::Id(id) => *id,
+            ByIdConstraint::Matching(query) => return Ok(FieldConstraint::Matching(query.as_ref())),
+        };
+        Ok(FieldConstraint::Equals(bincode::serde::encode_to_vec(id, BINCODE_CONFIG)?))
+    }
+}
+
+/// Decodes the type tag at the start of stored data, returning it with its encoded length.
+pub fn decode_tag(data: &[u8]) -> Result<(u64, usize), VaultError> {
+    Ok(bincode::serde::decode_from_slice(data, BINCODE_CONFIG)?)
 }
 
 // This function is needed because the syntax T::deserialize_value::<T>(...) is not allowed for
 // some types of T, such as Box<T>. This function provides a workaround.
-pub fn deserialize_type<T: VaultType>(data: &[u8], lookup_id: &dyn Fn (ValueId) -> Option<Vec<u8>>) -> Option<T> {
-    T::deserialize_value(&data, lookup_id).map(|(_serialized, val)| val)
+pub fn deserialize_type<T: VaultType>(data: &[u8], type_map: &TypeMap, lookup_id: &dyn Fn (ValueId) -> Result<Option<Vec<u8>>, VaultError>) -> Result<T, VaultError> {
+    T::deserialize_value(data, type_map, lookup_id).map(|(_serialized, val)| val)
+}
+
+// Serializes `value` with every value nested in it, as the ids of nested values are the hashes of
+// their contents.
+pub fn serialize_type<T: VaultType>(value: &T, type_map: &TypeMap) -> Result<Vec<(Vec<u8>, ValueId)>, VaultError> {
+    serialize_into_nested(value, NestedValues::new(), type_map)
 }
 
-pub fn serialize_type<T: VaultType>(value: &T, type_map: &TypeMap) -> Vec<(Vec<u8>, ValueId)> {
-    let mut nested_dest = vec![];
+// Like `serialize_type`, but leaves out the values nested in the `Shared` parts of `value` whose
+// ids `is_stored`, without serializing them again. The parts themselves are still returned.
+pub fn serialize_type_skipping<T: VaultType>(value: &T, type_map: &TypeMap, is_stored: &dyn Fn(ValueId) -> Result<bool, VaultError>) -> Result<Vec<(Vec<u8>, ValueId)>, VaultError> {
+    serialize_into_nested(value, NestedValues::skipping(is_stored), type_map)
+}
+
+fn serialize_into_nested<T: VaultType>(value: &T, mut nested_dest: NestedValues, type_map: &TypeMap) -> Result<Vec<(Vec<u8>, ValueId)>, VaultError> {
     let mut dest = vec![];
-    value.serialize_into(&mut nested_dest, &mut dest, &type_map);
-    let id = value_id_of(&dest);
-    nested_dest.push((dest, id));
-    nested_dest
+    value.serialize_into(&mut nested_dest, &mut dest, type_map)?;
+    let id = type_map.value_id_of(&dest);
+    nested_dest.push(dest, id);
+    Ok(nested_dest.values)
+}
+
+/// Collects the values nested in a value while it is serialized, see `VaultType::serialize_into`.
+#[derive(Default)]
+pub struct NestedValues<'s> {
+    values: Vec<(Vec<u8>, ValueId)>,
+    is_stored: Option<&'s dyn Fn(ValueId) -> Result<bool, VaultError>>,
+}
+
+impl<'s> NestedValues<'s> {
+    pub fn new() -> Self {
+        NestedValues::default()
+    }
+
+    /// Collects the values that are not nested in a `Shared` part whose id `is_stored`.
+    pub fn skipping(is_stored: &'s dyn Fn(ValueId) -> Result<bool, VaultError>) -> Self {
+        NestedValues { values: vec![], is_stored: Some(is_stored) }
+    }
+
+    pub fn push(&mut self, data: Vec<u8>, id: ValueId) {
+        self.values.push((data, id));
+    }
+
+    /// Whether the value stored under `id` is stored, with everything nested in it, so that it
+    /// doesn't need to be serialized again. Always false unless the values are collected
+    /// `skipping` stored ones.
+    pub fn is_stored(&self, id: ValueId) -> Result<bool, VaultError> {
+        match self.is_stored {
+            Some(is_stored) => is_stored(id),
+            None => Ok(false),
+        }
+    }
+}
+
+/// A value that can be nested in several others, and that remembers how it was serialized.
+///
+/// Values are stored by the hash of their contents, which hold the ids of the values nested in
+/// them, so finding out whether a value is stored means serializing all of it. A `Shared` part
+/// only does that once: `TypeVault::put` skips it, and everything nested in it, when it is
+/// stored already. Building a changed copy of a big value out of `Shared` parts, which keeps the
+/// parts that didn't change and only replaces those on the path to the change, therefore puts
+/// it in time in proportion to the length of that path. Like a `Box`, it is stored as the value
+/// it holds.
+pub struct Shared<T>(Arc<SharedValue<T>>);
+
+struct SharedValue<T> {
+    value: T,
+    // The serialization of `value`, with its id and the generation of the `TypeMap` it was
+    // serialized with.
+    serialized: OnceLock<(u64, Vec<u8>, ValueId)>,
+}
+
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Shared(Arc::new(SharedValue { value, serialized: OnceLock::new() }))
+    }
+
+    // Remembers `serialized` as the serialization of the value with `type_map`, unless another
+    // one was remembered first.
+    fn remember(&self, serialized: &[u8], type_map: &TypeMap) {
+        let _ = self.0.serialized.set((type_map.generation, serialized.to_vec(), type_map.value_id_of(serialized)));
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
+
+impl<T> Deref for Shared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0.value
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.value.fmt(f)
+    }
+}
+
+impl<T: PartialEq> PartialEq for Shared<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.value == other.0.value
+    }
 }
 
+impl<T: Eq> Eq for Shared<T> {}
+
 pub const BINCODE_CONFIG: bincode::config::Configuration<bincode::config::BigEndian> =
     bincode::config::standard().with_big_endian();
 
 impl<T: VaultType> VaultType for Box<T> {
     type InnerVaultType = T;
-    fn serialize_into(&self, nested_dest: &mut Vec<(Vec<u8>, ValueId)>, dest: &mut Vec<u8>, type_map: &TypeMap) {
-        (**self).serialize_into(nested_dest, dest, type_map);
+    type Fact = T::Fact;
+    const TYPE_NAME: &'static str = T::TYPE_NAME;
+    fn schema() -> Schema {
+        T::schema()
+    }
+    fn stored_type_name() -> String {
+        T::stored_type_name()
+    }
+    fn serialize_into(&self, nested_dest: &mut NestedValues, dest: &mut Vec<u8>, type_map: &TypeMap) -> Result<(), VaultError> {
+        (**self).serialize_into(nested_dest, dest, type_map)
     }
 
-    fn serialize_prefix(&self, fields_in_prefix: u64, type_map: &TypeMap) -> Vec<u8> {
+    fn serialize_prefix(&self, fields_in_prefix: u64, type_map: &TypeMap) -> Result<Vec<u8>, VaultError> {
         (**self).serialize_prefix(fields_in_prefix, type_map)
     }
-    fn deserialize_value<'a>(data: &'a [u8], lookup_id: &dyn Fn (ValueId) -> Option<Vec<u8>>) -> Option<(&'a [u8],Self)> where Self: Sized {
-        T::deserialize_value(data, lookup_id).map(|(serialized, val)| (serialized, Box::new(val)))
+    fn deserialize_value<'a>(data: &'a [u8], type_map: &TypeMap, lookup_id: &dyn Fn (ValueId) -> Result<Option<Vec<u8>>, VaultError>) -> Result<(&'a [u8],Self), VaultError> where Self: Sized {
+        T::deserialize_value(data, type_map, lookup_id).map(|(serialized, val)| (serialized, Box::new(val)))
+    }
+    fn remap_fact_ids(data: &[u8], remap: &mut dyn FnMut(ValueId) -> Result<ValueId, VaultError>) -> Result<(Vec<u8>, usize), VaultError> {
+        T::remap_fact_ids(data, remap)
+    }
+    fn encoded_fields(fact: &[u8]) -> Result<Vec<Vec<u8>>, VaultError> {
+        T::encoded_fields(fact)
+    }
+    fn indexed_fields(fact: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, VaultError> {
+        T::indexed_fields(fact)
+    }
+}
+
+impl<T: VaultType> VaultType for Shared<T> {
+    type InnerVaultType = T;
+    type Fact = T::Fact;
+    const TYPE_NAME: &'static str = T::TYPE_NAME;
+    fn schema() -> Schema {
+        T::schema()
+    }
+    fn stored_type_name() -> String {
+        T::stored_type_name()
+    }
+    fn serialize_into(&self, nested_dest: &mut NestedValues, dest: &mut Vec<u8>, type_map: &TypeMap) -> Result<(), VaultError> {
+        if let Some((generation, serialized, id)) = self.0.serialized.get() {
+            if *generation == type_map.generation && nested_dest.is_stored(*id)? {
+                dest.extend_from_slice(serialized);
+                return Ok(());
+            }
+        }
+        let start = dest.len();
+        self.0.value.serialize_into(nested_dest, dest, type_map)?;
+        self.remember(&dest[start..], type_map);
+        Ok(())
+    }
+
+    fn serialize_prefix(&self, fields_in_prefix: u64, type_map: &TypeMap) -> Result<Vec<u8>, VaultError> {
+        self.0.value.serialize_prefix(fields_in_prefix, type_map)
+    }
+    // Values read back remember the data they were read from, so that they are skipped when they
+    // are put again.
+    fn deserialize_value<'a>(data: &'a [u8], type_map: &TypeMap, lookup_id: &dyn Fn (ValueId) -> Result<Option<Vec<u8>>, VaultError>) -> Result<(&'a [u8],Self), VaultError> where Self: Sized {
+        let (rest, value) = T::deserialize_value(data, type_map, lookup_id)?;
+        let shared = Shared::new(value);
+        shared.remember(&data[..data.len() - rest.len()], type_map);
+        Ok((rest, shared))
+    }
+    fn remap_fact_ids(data: &[u8], remap: &mut dyn FnMut(ValueId) -> Result<ValueId, VaultError>) -> Result<(Vec<u8>, usize), VaultError> {
+        T::remap_fact_ids(data, remap)
+    }
+    fn encoded_fields(fact: &[u8]) -> Result<Vec<Vec<u8>>, VaultError> {
+        T::encoded_fields(fact)
+    }
+    fn indexed_fields(fact: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, VaultError> {
+        T::indexed_fields(fact)
     }
 }
 
 impl<T: VaultType, U: VaultType> VaultType for (T,U) {
     type InnerVaultType = U;
-    fn serialize_into(&self, nested_dest: &mut Vec<(Vec<u8>, ValueId)>, dest: &mut Vec<u8>, type_map: &TypeMap) {
-        self.0.serialize_into(nested_dest, dest, type_map);
-        self.1.serialize_into(nested_dest, dest, type_map);
+    type Fact = ();
+    const TYPE_NAME: &'static str = "(,)";
+    // Only derived types are stored under a tag of their own, so this is never persisted.
+    fn schema() -> Schema {
+        Schema::Struct(vec![])
+    }
+    fn stored_type_name() -> String {
+        format!("({},{})", T::stored_type_name(), U::stored_type_name())
+    }
+    fn serialize_into(&self, nested_dest: &mut NestedValues, dest: &mut Vec<u8>, type_map: &TypeMap) -> Result<(), VaultError> {
+        self.0.serialize_into(nested_dest, dest, type_map)?;
+        self.1.serialize_into(nested_dest, dest, type_map)
     }
 
-    fn serialize_prefix(&self, _fields_in_prefix: u64, _type_map: &TypeMap) -> Vec<u8> {
+    fn serialize_prefix(&self, _fields_in_prefix: u64, _type_map: &TypeMap) -> Result<Vec<u8>, VaultError> {
         // This method is only meant for structs.
-        panic!("Prefix serialization not supported for tuples");
+        Err(VaultError::Unsupported("prefix serialization of tuples"))
     }
 
-    fn deserialize_value<'a>(data: &'a [u8], lookup_id: &dyn Fn (ValueId) -> Option<Vec<u8>>) -> Option<(&'a [u8],Self)> where Self: Sized {
-        let (more_data, first) =
-            match T::deserialize_value(data, &lookup_id) {
-                None => {
-                    eprintln!("Failed to decode first element of tuple");
-                    return None
-                },
-                Some((more_data, val)) => (more_data, val),
-        };
-        let (rest, second) =
-            match U::deserialize_value(&more_data, lookup_id) {
-                None => {
-                    eprintln!("Failed to decode second element of tuple");
-                    return None
-                },
-                Some((rest, val)) => (rest, val),
-        };
-        Some((rest, (first, second)))
+    fn deserialize_value<'a>(data: &'a [u8], type_map: &TypeMap, lookup_id: &dyn Fn (ValueId) -> Result<Option<Vec<u8>>, VaultError>) -> Result<(&'a [u8],Self), VaultError> where Self: Sized {
+        let (more_data, first) = T::deserialize_value(data, type_map, lookup_id)?;
+        let (rest, second) = U::deserialize_value(more_data, type_map, lookup_id)?;
+        Ok((rest, (first, second)))
+    }
+
+    fn remap_fact_ids(_data: &[u8], _remap: &mut dyn FnMut(ValueId) -> Result<ValueId, VaultError>) -> Result<(Vec<u8>, usize), VaultError> {
+        Err(VaultError::Unsupported("tuples have no fact of their own"))
+    }
+
+    fn encoded_fields(_fact: &[u8]) -> Result<Vec<Vec<u8>>, VaultError> {
+        Ok(vec![])
+    }
+
+    fn indexed_fields(_fact: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, VaultError> {
+        Ok(vec![])
     }
 }
 
 
 impl<T: VaultType> VaultType for Option<T> {
     type InnerVaultType = T;
-    fn serialize_prefix(&self, _fields_in_prefix: u64, _type_map: &TypeMap) -> Vec<u8> {
-        panic!("Prefix serialization not supported for Option types");
+    type Fact = ();
+    const TYPE_NAME: &'static str = "Option";
+    // Only derived types are stored under a tag of their own, so this is never persisted.
+    fn schema() -> Schema {
+        Schema::Struct(vec![])
+    }
+    fn stored_type_name() -> String {
+        format!("Option<{}>", T::stored_type_name())
+    }
+    fn serialize_prefix(&self, _fields_in_prefix: u64, _type_map: &TypeMap) -> Result<Vec<u8>, VaultError> {
+        Err(VaultError::Unsupported("prefix serialization of Option types"))
     }
 
-    fn serialize_into(&self, nested_dest: &mut Vec<(Vec<u8>, ValueId)>, dest: &mut Vec<u8>, type_map: &TypeMap) {
+    fn serialize_into(&self, nested_dest: &mut NestedValues, dest: &mut Vec<u8>, type_map: &TypeMap) -> Result<(), VaultError> {
         match self {
             Some(inner) => {
                 dest.push(1u8); // Prefix with a 1 byte to indicate Some
-                inner.serialize_into(nested_dest, dest, type_map);
+                inner.serialize_into(nested_dest, dest, type_map)
             },
             None => {
                 dest.push(0u8); // Represent None as a zero byte
+                Ok(())
             },
         }
     }
 
-    fn deserialize_value<'a>(data: &'a [u8], lookup_id: &dyn Fn (ValueId) -> Option<Vec<u8>>) -> Option<(&'a [u8], Self)> where Self: Sized {
-        if data.is_empty() {
-            eprintln!("Data is empty when trying to deserialize Option");
-            return None;
-        }
-        match data[0] {
-            0 => Some((data, None)), // None case
-            1 => {
-                let (rest, inner) = T::deserialize_value(&data[1..], lookup_id)?;
-                Some((rest, Some(inner)))
-            },
-            _ => {
-                eprintln!("Invalid prefix byte {} when deserializing Option", data[0]);
-                None
+    fn deserialize_value<'a>(data: &'a [u8], type_map: &TypeMap, lookup_id: &dyn Fn (ValueId) -> Result<Option<Vec<u8>>, VaultError>) -> Result<(&'a [u8], Self), VaultError> where Self: Sized {
+        match data.first() {
+            None => Err(VaultError::Truncated(std::any::type_name::<Self>())),
+            Some(0) => Ok((&data[1..], None)),
+            Some(1) => {
+                let (rest, inner) = T::deserialize_value(&data[1..], type_map, lookup_id)?;
+                Ok((rest, Some(inner)))
             },
+            Some(byte) => Err(VaultError::Corrupt(format!("invalid prefix byte {} when deserializing Option", byte))),
         }
     }
+
+    fn remap_fact_ids(_data: &[u8], _remap: &mut dyn FnMut(ValueId) -> Result<ValueId, VaultError>) -> Result<(Vec<u8>, usize), VaultError> {
+        Err(VaultError::Unsupported("Option types have no fact of their own"))
+    }
+
+    fn encoded_fields(_fact: &[u8]) -> Result<Vec<Vec<u8>>, VaultError> {
+        Ok(vec![])
+    }
+
+    fn indexed_fields(_fact: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, VaultError> {
+        Ok(vec![])
+    }
 }
 
 impl VaultType for () {
     type InnerVaultType = ();
-    fn serialize_into(&self, _nested_dest: &mut Vec<(Vec<u8>, ValueId)>, _dest: &mut Vec<u8>, _type_map: &TypeMap) {
-        return
+    type Fact = ();
+    const TYPE_NAME: &'static str = "()";
+    // Only derived types are stored under a tag of their own, so this is never persisted.
+    fn schema() -> Schema {
+        Schema::Struct(vec![])
+    }
+    fn stored_type_name() -> String {
+        "()".to_owned()
+    }
+    fn serialize_into(&self, _nested_dest: &mut NestedValues, _dest: &mut Vec<u8>, _type_map: &TypeMap) -> Result<(), VaultError> {
+        Ok(())
+    }
+
+    fn serialize_prefix(&self, _fields_in_prefix: u64, _type_map: &TypeMap) -> Result<Vec<u8>, VaultError> {
+        Ok(vec![])
+    }
+
+    fn deserialize_value<'a>(data: &'a [u8], _type_map: &TypeMap, _lookup_id: &dyn Fn (ValueId) -> Result<Option<Vec<u8>>, VaultError>) -> Result<(&'a [u8], Self), VaultError> where Self: Sized {
+        Ok((data, ()))
     }
 
-    fn serialize_prefix(&self, _fields_in_prefix: u64, _type_map: &TypeMap) -> Vec<u8> {
-        vec![]
+    fn remap_fact_ids(_data: &[u8], _remap: &mut dyn FnMut(ValueId) -> Result<ValueId, VaultError>) -> Result<(Vec<u8>, usize), VaultError> {
+        Ok((vec![], 0))
     }
 
-    fn deserialize_value<'a>(data: &'a [u8], _lookup_id: &dyn Fn (ValueId) -> Option<Vec<u8>>) -> Option<(&'a [u8], Self)> where Self: Sized {
-        Some((data, ()))
+    fn encoded_fields(_fact: &[u8]) -> Result<Vec<Vec<u8>>, VaultError> {
+        Ok(vec![])
     }
-}
\ No newline at end of file
+
+    fn indexed_fields(_fact: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, VaultError> {
+        Ok(vec![])
+    }
+}
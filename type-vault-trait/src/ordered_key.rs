@@ -0,0 +1,175 @@
+//! The encoding of primitive fields in facts. Values are encoded with a fixed width, big-endian,
+//! with the sign bit of integers flipped and floats mapped to a total order, so that comparing the
+//! encodings byte by byte orders them the same as comparing the values. Since facts are keys of
+//! the vault, this keeps the values of a type sorted by their leading fields, and index entries
+//! sorted by the indexed field.
+//!
+//! The derive macro applies it to primitive fields with `#[serde(with = "ordered_key")]`.
+
+use crate::{VaultError, BINCODE_CONFIG};
+use serde::de::{Deserialize, Deserializer, Error, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+use std::{fmt, marker::PhantomData};
+
+/// A primitive field type, which is encoded so that its encodings sort like its values.
+pub trait OrderedKey: Sized {
+    fn serialize_ordered<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>;
+    fn deserialize_ordered<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>;
+}
+
+pub fn serialize<T: OrderedKey, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    value.serialize_ordered(serializer)
+}
+
+pub fn deserialize<'de, T: OrderedKey, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+    T::deserialize_ordered(deserializer)
+}
+
+/// Encodes a field value the way it is encoded in facts.
+pub fn encode_key<T: OrderedKey>(value: &T) -> Result<Vec<u8>, VaultError> {
+    Ok(bincode::serde::encode_to_vec(Ordered(value), BINCODE_CONFIG)?)
+}
+
+struct Ordered<'a, T>(&'a T);
+
+impl<T: OrderedKey> Serialize for Ordered<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize_ordered(serializer)
+    }
+}
+
+struct OrderedValue<T>(T);
+
+impl<'de, T: OrderedKey> Deserialize<'de> for OrderedValue<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize_ordered(deserializer).map(OrderedValue)
+    }
+}
+
+// Byte arrays are serialized as tuples of bytes, which take up exactly their length.
+macro_rules! ordered_int {
+    ($($ty:ty => $flip:expr),*) => {$(
+        impl OrderedKey for $ty {
+            fn serialize_ordered<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                (*self ^ $flip).to_be_bytes().serialize(serializer)
+            }
+
+            fn deserialize_ordered<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let bytes = <[u8; std::mem::size_of::<$ty>()]>::deserialize(deserializer)?;
+                Ok(<$ty>::from_be_bytes(bytes) ^ $flip)
+            }
+        }
+    )*};
+}
+
+ordered_int!(u8 => 0, u16 => 0, u32 => 0, u64 => 0, u128 => 0,
+    i8 => i8::MIN, i16 => i16::MIN, i32 => i32::MIN, i64 => i64::MIN, i128 => i128::MIN);
+
+// Positive floats sort after negative ones by setting the sign bit, and negative floats sort in
+// reverse by flipping all bits.
+macro_rules! ordered_float {
+    ($($ty:ty => $bits:ty),*) => {$(
+        impl OrderedKey for $ty {
+            fn serialize_ordered<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let bits = self.to_bits();
+                let sign = 1 << (<$bits>::BITS - 1);
+                let key = if bits & sign != 0 { !bits } else { bits | sign };
+                key.to_be_bytes().serialize(serializer)
+            }
+
+            fn deserialize_ordered<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let key = <$bits>::from_be_bytes(<[u8; std::mem::size_of::<$bits>()]>::deserialize(deserializer)?);
+                let sign = 1 << (<$bits>::BITS - 1);
+                let bits = if key & sign != 0 { key & !sign } else { !key };
+                Ok(<$ty>::from_bits(bits))
+            }
+        }
+    )*};
+}
+
+ordered_float!(f32 => u32, f64 => u64);
+
+impl OrderedKey for bool {
+    fn serialize_ordered<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        u8::from(*self).serialize(serializer)
+    }
+
+    fn deserialize_ordered<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match u8::deserialize(deserializer)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(D::Error::custom(format!("invalid bool {}", byte))),
+        }
+    }
+}
+
+impl OrderedKey for () {
+    fn serialize_ordered<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit()
+    }
+
+    fn deserialize_ordered<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <()>::deserialize(deserializer)
+    }
+}
+
+// Tuples are encoded as their elements one after the other, so they sort by their first element,
+// then their second and so on.
+macro_rules! ordered_tuple {
+    ($(($($ty:ident $var:ident $index:tt),+)),*) => {$(
+        impl<$($ty: OrderedKey),+> OrderedKey for ($($ty,)+) {
+            fn serialize_ordered<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                ($(Ordered(&self.$index),)+).serialize(serializer)
+            }
+
+            fn deserialize_ordered<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let ($(OrderedValue($var),)+) = <($(OrderedValue<$ty>,)+)>::deserialize(deserializer)?;
+                Ok(($($var,)+))
+            }
+        }
+    )*};
+}
+
+ordered_tuple!(
+    (T0 v0 0),
+    (T0 v0 0, T1 v1 1),
+    (T0 v0 0, T1 v1 1, T2 v2 2),
+    (T0 v0 0, T1 v1 1, T2 v2 2, T3 v3 3),
+    (T0 v0 0, T1 v1 1, T2 v2 2, T3 v3 3, T4 v4 4),
+    (T0 v0 0, T1 v1 1, T2 v2 2, T3 v3 3, T4 v4 4, T5 v5 5),
+    (T0 v0 0, T1 v1 1, T2 v2 2, T3 v3 3, T4 v4 4, T5 v5 5, T6 v6 6),
+    (T0 v0 0, T1 v1 1, T2 v2 2, T3 v3 3, T4 v4 4, T5 v5 5, T6 v6 6, T7 v7 7)
+);
+
+impl<T: OrderedKey, const N: usize> OrderedKey for [T; N] {
+    fn serialize_ordered<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(N)?;
+        for element in self {
+            tuple.serialize_element(&Ordered(element))?;
+        }
+        tuple.end()
+    }
+
+    fn deserialize_ordered<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ArrayVisitor<T, const N: usize>(PhantomData<T>);
+
+        impl<'de, T: OrderedKey, const N: usize> Visitor<'de> for ArrayVisitor<T, N> {
+            type Value = [T; N];
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an array of length {}", N)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<[T; N], A::Error> {
+                let mut elements = Vec::with_capacity(N);
+                for i in 0..N {
+                    let OrderedValue(element) = seq.next_element()?.ok_or_else(|| A::Error::invalid_length(i, &self))?;
+                    elements.push(element);
+                }
+                Ok(elements.try_into().unwrap_or_else(|_| unreachable!("exactly N elements were read")))
+            }
+        }
+
+        deserializer.deserialize_tuple(N, ArrayVisitor(PhantomData))
+    }
+}
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How a field is kept in the `<Name>Fact` that is stored for its type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FieldStorage {
+    /// Primitive fields are encoded directly in the fact.
+    Inline,
+    /// All other fields are stored as values of their own and referenced by their `ValueId`.
+    ById,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldSchema {
+    /// The field name, or its position for tuple structs and tuple variants.
+    pub name: String,
+    /// For inline fields, the Rust type of the field as written in the type definition. For fields
+    /// stored by id, the name of the stored type, see `VaultType::stored_type_name`.
+    pub ty: String,
+    pub storage: FieldStorage,
+    /// Whether the field is marked `#[vault(index)]`. Indexes don't change how values are
+    /// stored, so this has no bearing on compatibility.
+    pub indexed: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VariantSchema {
+    pub name: String,
+    pub fields: Vec<FieldSchema>,
+}
+
+/// Describes how the values of a `VaultType` are stored. The derive macro generates it from the
+/// type definition, and vaults keep the schema of every type they store so they can tell when a
+/// type definition no longer matches the stored values.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Schema {
+    Struct(Vec<FieldSchema>),
+    Enum(Vec<VariantSchema>),
+}
+
+/// The ways in which the definition of a registered type no longer matches its stored values.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SchemaMismatch {
+    pub type_name: &'static str,
+    pub differences: Vec<String>,
+}
+
+impl fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.type_name, self.differences.join(", "))
+    }
+}
+
+impl Schema {
+    /// The positions of the indexed fields of a struct. Fields of enums are never indexed.
+    pub fn indexed_fields(&self) -> Vec<u64> {
+        match self {
+            Schema::Struct(fields) => (0..).zip(fields).filter(|(_, field)| field.indexed).map(|(i, _)| i).collect(),
+            Schema::Enum(_) => vec![],
+        }
+    }
+
+    /// Lists the differences that keep values stored with the `stored` schema from being decoded
+    /// with this one. Fields and variants are encoded by position, so renaming them is harmless,
+    /// but a name that is used at another position than before means they were reordered, which
+    /// would decode each stored field or variant as another one.
+    pub fn incompatibilities(&self, stored: &Schema) -> Vec<String> {
+        let mut differences = vec![];
+        match (stored, self) {
+            (Schema::Struct(stored_fields), Schema::Struct(fields)) =>
+                fields_incompatibilities("", stored_fields, fields, &mut differences),
+            (Schema::Enum(stored_variants), Schema::Enum(variants)) => {
+                if stored_variants.len() != variants.len() {
+                    differences.push(format!("number of variants changed from {} to {}", stored_variants.len(), variants.len()));
+                }
+                let names = |variants: &[VariantSchema]| variants.iter().map(|variant| variant.name.clone()).collect::<Vec<_>>();
+                moved_names("", "variant", &names(stored_variants), &names(variants), &mut differences);
+                for (stored_variant, variant) in stored_variants.iter().zip(variants) {
+                    let context = format!("variant `{}`: ", variant.name);
+                    fields_incompatibilities(&context, &stored_variant.fields, &variant.fields, &mut differences);
+                }
+            },
+            (Schema::Struct(_), Schema::Enum(_)) => differences.push("changed from a struct to an enum".to_owned()),
+            (Schema::Enum(_), Schema::Struct(_)) => differences.push("changed from an enum to a struct".to_owned()),
+        }
+        differences
+    }
+}
+
+fn fields_incompatibilities(context: &str, stored_fields: &[FieldSchema], fields: &[FieldSchema], differences: &mut Vec<String>) {
+    if stored_fields.len() != fields.len() {
+        differences.push(format!("{}number of fields changed from {} to {}", context, stored_fields.len(), fields.len()));
+    }
+    let names = |fields: &[FieldSchema]| fields.iter().map(|field| field.name.clone()).collect::<Vec<_>>();
+    moved_names(context, "field", &names(stored_fields), &names(fields), differences);
+    for (stored_field, field) in stored_fields.iter().zip(fields) {
+        if stored_field.storage != field.storage {
+            differences.push(format!("{}field `{}` changed from being stored {} to {}", context, field.name,
+                storage_name(stored_field.storage), storage_name(field.storage)));
+        } else if stored_field.ty != field.ty {
+            differences.push(format!("{}field `{}` changed type from `{}` to `{}`", context, field.name, stored_field.ty, field.ty));
+        }
+    }
+}
+
+// Reports the names in `stored_names` that are at another position in `names`.
+fn moved_names(context: &str, kind: &str, stored_names: &[String], names: &[String], differences: &mut Vec<String>) {
+    for (stored_position, name) in stored_names.iter().enumerate() {
+        if let Some(position) = names.iter().position(|new_name| new_name == name).filter(|position| *position != stored_position) {
+            differences.push(format!("{}{} `{}` moved from position {} to {}", context, kind, name, stored_position, position));
+        }
+    }
+}
+
+fn storage_name(storage: FieldStorage) -> &'static str {
+    match storage {
+        FieldStorage::Inline => "inline",
+        FieldStorage::ById => "by id",
+    }
+}
@@ -0,0 +1,232 @@
+//! The key-value stores a vault keeps its trees in. A `TypeVault` only needs ordered trees of
+//! byte keys and values with atomic batches, and transactions that span several trees, so any
+//! store that offers those can back it.
+
+pub mod memory;
+pub mod sled;
+#[cfg(feature = "redb")]
+pub mod redb;
+
+use type_vault_trait::VaultError;
+use std::{
+    future::Future,
+    ops::Bound,
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+/// The key and value of an entry of a tree.
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// The entries of a tree, in the order of their keys.
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<Entry, VaultError>> + 'a>;
+
+/// A store of named trees.
+pub trait VaultBackend {
+    type Tree: VaultTree;
+
+    /// Opens the tree with the given name, creating it if it doesn't exist yet.
+    fn open_tree(&self, name: &str) -> Result<Self::Tree, VaultError>;
+
+    /// Runs `f` as a single atomic transaction over `trees`: either all of its writes are applied
+    /// or none of them are. `f` reaches the trees by their position in `trees`, and may be called
+    /// more than once if the transaction conflicts with a concurrent one.
+    fn transaction<R>(&self, trees: &[&Self::Tree], f: &dyn Fn(&dyn BackendTransaction) -> Result<R, VaultError>) -> Result<R, VaultError>;
+
+    /// A number that is larger than any returned before, here or by
+    /// `BackendTransaction::generate_id`.
+    fn generate_id(&self) -> Result<u64, VaultError>;
+
+    /// `count` numbers like those of `generate_id`, in increasing order. Backends for which each
+    /// `generate_id` is a write of its own hand them out with a single one.
+    fn generate_ids(&self, count: usize) -> Result<Vec<u64>, VaultError> {
+        (0..count).map(|_| self.generate_id()).collect()
+    }
+
+    /// Waits until every write so far is durable. Backends that make every write durable as it
+    /// is made have nothing to do.
+    fn flush(&self) -> Result<(), VaultError> {
+        Ok(())
+    }
+
+    /// Like `flush`, without blocking.
+    fn flush_async(&self) -> impl Future<Output = Result<(), VaultError>> + '_ {
+        std::future::ready(self.flush())
+    }
+}
+
+/// A tree of a `VaultBackend`: a map of byte keys to byte values, ordered by key.
+pub trait VaultTree {
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, VaultError>;
+
+    fn insert(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), VaultError>;
+
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<(), VaultError>;
+
+    /// The entries with keys within `range`, which is empty if it starts after it ends.
+    fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Entries<'_>;
+
+    /// Removes every entry.
+    fn clear(&self) -> Result<(), VaultError>;
+
+    /// Applies all writes of `batch` at once.
+    fn apply_batch(&self, batch: Batch) -> Result<(), VaultError>;
+
+    /// Watches for entries with keys that start with `prefix` to be inserted from now on, by
+    /// writes through any handle on the same store, batches and transactions included.
+    fn watch_prefix(&self, prefix: &[u8]) -> Result<Box<dyn PrefixWatch>, VaultError>;
+
+    fn contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool, VaultError> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// The entries with keys that start with `prefix`.
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Entries<'_> {
+        let end = match prefix_end(prefix.as_ref()) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.range((Bound::Included(prefix.as_ref().to_vec()), end))
+    }
+
+    fn iter(&self) -> Entries<'_> {
+        self.range((Bound::Unbounded, Bound::Unbounded))
+    }
+
+    fn is_empty(&self) -> Result<bool, VaultError> {
+        Ok(self.iter().next().transpose()?.is_none())
+    }
+}
+
+/// The entries inserted into a tree under a prefix, see `VaultTree::watch_prefix`.
+pub trait PrefixWatch: Send {
+    /// Waits for the next entry to be inserted, for at most `timeout` if there is one, and returns
+    /// its key and value. Returns `None` if none was inserted in time.
+    fn next_insert(&mut self, timeout: Option<Duration>) -> Option<Entry>;
+}
+
+/// The trees of a transaction, see `VaultBackend::transaction`.
+pub trait BackendTransaction {
+    fn get(&self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, VaultError>;
+
+    fn insert(&self, tree: usize, key: &[u8], value: &[u8]) -> Result<(), VaultError>;
+
+    /// Removes the entry with the given key, returning its value.
+    fn remove(&self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, VaultError>;
+
+    /// A number that is larger than any returned before in transactions that went through.
+    fn generate_id(&self) -> Result<u64, VaultError>;
+}
+
+/// Writes to a single tree that are applied together, see `VaultTree::apply_batch`.
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+    writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl Batch {
+    pub fn insert(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.writes.push((key.into(), Some(value.into())));
+    }
+
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        self.writes.push((key.into(), None));
+    }
+
+    /// The keys with their new values, or `None` for removed keys, in the order they were written.
+    pub fn writes(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+        self.writes.iter().map(|(key, value)| (key.as_slice(), value.as_deref()))
+    }
+}
+
+// A tree within a transaction, see `BackendTransaction`.
+#[derive(Clone, Copy)]
+pub(crate) struct TransactionTree<'a> {
+    pub(crate) tx: &'a dyn BackendTransaction,
+    pub(crate) tree: usize,
+}
+
+impl TransactionTree<'_> {
+    pub(crate) fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, VaultError> {
+        self.tx.get(self.tree, key.as_ref())
+    }
+
+    pub(crate) fn insert(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), VaultError> {
+        self.tx.insert(self.tree, key.as_ref(), value.as_ref())
+    }
+
+    pub(crate) fn remove(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, VaultError> {
+        self.tx.remove(self.tree, key.as_ref())
+    }
+
+    pub(crate) fn apply_batch(&self, batch: &Batch) -> Result<(), VaultError> {
+        for (key, value) in batch.writes() {
+            match value {
+                Some(value) => self.insert(key, value)?,
+                None => self.remove(key).map(|_| ())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Emulates `VaultTree::watch_prefix` for backends that can't watch their trees. Clones share their
+// watches, and every write to the tree, whether through the tree itself or a transaction, has to
+// report the entries it inserted once they are committed.
+#[derive(Clone, Default)]
+pub(crate) struct Watches {
+    watches: Arc<Mutex<Vec<Watch>>>,
+}
+
+// The prefix of a watch, with where to send the entries inserted under it.
+type Watch = (Vec<u8>, mpsc::Sender<Entry>);
+
+impl Watches {
+    pub(crate) fn watch(&self, prefix: &[u8]) -> Box<dyn PrefixWatch> {
+        let (sender, inserted) = mpsc::channel();
+        self.watches.lock().unwrap_or_else(PoisonError::into_inner).push((prefix.to_vec(), sender));
+        Box::new(ChannelWatch { inserted })
+    }
+
+    // Hands an inserted entry to the watches of its prefixes, and forgets the watches that were
+    // dropped.
+    pub(crate) fn inserted(&self, key: &[u8], value: &[u8]) {
+        let mut watches = self.watches.lock().unwrap_or_else(PoisonError::into_inner);
+        watches.retain(|(prefix, sender)| !key.starts_with(prefix) || sender.send((key.to_vec(), value.to_vec())).is_ok());
+    }
+}
+
+struct ChannelWatch {
+    inserted: mpsc::Receiver<Entry>,
+}
+
+impl PrefixWatch for ChannelWatch {
+    fn next_insert(&mut self, timeout: Option<Duration>) -> Option<Entry> {
+        match timeout {
+            Some(timeout) => self.inserted.recv_timeout(timeout).ok(),
+            None => self.inserted.recv().ok(),
+        }
+    }
+}
+
+// The first key after every key that starts with `prefix`, if there is one.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+// Whether no key lies within `range`, which backends may not accept.
+pub(crate) fn is_empty_range(range: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> bool {
+    match range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
@@ -0,0 +1,182 @@
+use super::{is_empty_range, Batch, BackendTransaction, Entries, PrefixWatch, VaultBackend, VaultTree, Watches};
+use type_vault_trait::VaultError;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+};
+
+// New values by key, with `None` for removed keys.
+type Writes = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// Keeps the trees of a vault in `BTreeMap`s in memory, so that nothing is ever written to disk
+/// and everything is gone once the vault and every clone of its backend are dropped. Clones share
+/// their trees, so a vault can be opened again on a clone. Transactions run one at a time.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    trees: Mutex<HashMap<String, MemoryTree>>,
+    // Held while a transaction runs.
+    transaction_lock: Mutex<()>,
+    next_id: AtomicU64,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// A tree of a `MemoryBackend`. Trees opened under the same name share their entries and the
+/// watches on them.
+#[derive(Clone, Default)]
+pub struct MemoryTree {
+    entries: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
+    watches: Watches,
+}
+
+impl VaultBackend for MemoryBackend {
+    type Tree = MemoryTree;
+
+    fn open_tree(&self, name: &str) -> Result<MemoryTree, VaultError> {
+        let mut trees = self.shared.trees.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(trees.entry(name.to_owned()).or_default().clone())
+    }
+
+    // Writes are kept aside until `f` is done, and then applied to all trees at once.
+    fn transaction<R>(&self, trees: &[&MemoryTree], f: &dyn Fn(&dyn BackendTransaction) -> Result<R, VaultError>) -> Result<R, VaultError> {
+        let _running = self.shared.transaction_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let tx = MemoryTransaction {
+            trees,
+            writes: trees.iter().map(|_| RefCell::default()).collect(),
+            next_id: &self.shared.next_id,
+        };
+        let result = f(&tx)?;
+        let writes: Vec<Writes> = tx.writes.into_iter().map(RefCell::into_inner).collect();
+        let mut entries: Vec<_> = trees.iter()
+            .map(|tree| tree.entries.write().unwrap_or_else(PoisonError::into_inner))
+            .collect();
+        for (entries, writes) in entries.iter_mut().zip(&writes) {
+            for (key, value) in writes {
+                match value {
+                    Some(value) => entries.insert(key.clone(), value.clone()),
+                    None => entries.remove(key),
+                };
+            }
+        }
+        drop(entries);
+        for (tree, writes) in trees.iter().zip(&writes) {
+            for (key, value) in writes {
+                if let Some(value) = value {
+                    tree.watches.inserted(key, value);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn generate_id(&self) -> Result<u64, VaultError> {
+        Ok(self.shared.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn generate_ids(&self, count: usize) -> Result<Vec<u64>, VaultError> {
+        let first = self.shared.next_id.fetch_add(count as u64, Ordering::Relaxed);
+        Ok((first..first + count as u64).collect())
+    }
+}
+
+impl VaultTree for MemoryTree {
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, VaultError> {
+        Ok(self.entries.read().unwrap_or_else(PoisonError::into_inner).get(key.as_ref()).cloned())
+    }
+
+    fn insert(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), VaultError> {
+        self.entries.write().unwrap_or_else(PoisonError::into_inner).insert(key.as_ref().to_vec(), value.as_ref().to_vec());
+        self.watches.inserted(key.as_ref(), value.as_ref());
+        Ok(())
+    }
+
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<(), VaultError> {
+        self.entries.write().unwrap_or_else(PoisonError::into_inner).remove(key.as_ref());
+        Ok(())
+    }
+
+    // Looks up one entry at a time, so that the tree isn't locked while the entries are used.
+    fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Entries<'_> {
+        if is_empty_range(&range) {
+            return Box::new(std::iter::empty());
+        }
+        let (mut start, end) = range;
+        Box::new(std::iter::from_fn(move || {
+            let entries = self.entries.read().unwrap_or_else(PoisonError::into_inner);
+            let (key, value) = entries.range((start.clone(), end.clone())).next()?;
+            start = Bound::Excluded(key.clone());
+            Some(Ok((key.clone(), value.clone())))
+        }))
+    }
+
+    fn clear(&self) -> Result<(), VaultError> {
+        self.entries.write().unwrap_or_else(PoisonError::into_inner).clear();
+        Ok(())
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), VaultError> {
+        let mut entries = self.entries.write().unwrap_or_else(PoisonError::into_inner);
+        for (key, value) in batch.writes() {
+            match value {
+                Some(value) => entries.insert(key.to_vec(), value.to_vec()),
+                None => entries.remove(key),
+            };
+        }
+        drop(entries);
+        for (key, value) in batch.writes() {
+            if let Some(value) = value {
+                self.watches.inserted(key, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn watch_prefix(&self, prefix: &[u8]) -> Result<Box<dyn PrefixWatch>, VaultError> {
+        Ok(self.watches.watch(prefix))
+    }
+}
+
+struct MemoryTransaction<'a> {
+    trees: &'a [&'a MemoryTree],
+    // The writes of the transaction to each tree.
+    writes: Vec<RefCell<Writes>>,
+    next_id: &'a AtomicU64,
+}
+
+impl BackendTransaction for MemoryTransaction<'_> {
+    fn get(&self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, VaultError> {
+        match self.writes[tree].borrow().get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.trees[tree].get(key),
+        }
+    }
+
+    fn insert(&self, tree: usize, key: &[u8], value: &[u8]) -> Result<(), VaultError> {
+        self.writes[tree].borrow_mut().insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn remove(&self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, VaultError> {
+        let removed = self.get(tree, key)?;
+        self.writes[tree].borrow_mut().insert(key.to_vec(), None);
+        Ok(removed)
+    }
+
+    fn generate_id(&self) -> Result<u64, VaultError> {
+        Ok(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,216 @@
+use super::{is_empty_range, Batch, BackendTransaction, Entries, Entry, PrefixWatch, VaultBackend, VaultTree, Watches};
+use type_vault_trait::VaultError;
+use redb::{ReadableTable, TableDefinition, TableError};
+use std::{cell::RefCell, collections::HashMap, ops::Bound, sync::{Arc, Mutex, PoisonError}};
+
+type Definition<'a> = TableDefinition<'a, &'static [u8], &'static [u8]>;
+type ReadOnlyTable = redb::ReadOnlyTable<&'static [u8], &'static [u8]>;
+
+// Holds the counter behind `BackendTransaction::generate_id`.
+const IDS_TABLE: Definition = TableDefinition::new("__generated_ids");
+
+/// Keeps the trees of a vault as tables of a redb database. Every write is a transaction of its
+/// own, and transactions never conflict, as redb runs one write transaction at a time. Clones
+/// share the database.
+///
+/// redb can't watch its tables, so `VaultTree::watch_prefix` only sees the writes made through
+/// this backend and its clones.
+#[derive(Clone)]
+pub struct RedbBackend {
+    db: Arc<redb::Database>,
+    // The watches on each table, by its name.
+    watches: Arc<Mutex<HashMap<String, Watches>>>,
+}
+
+impl RedbBackend {
+    pub fn open(path: &std::path::Path) -> Result<Self, VaultError> {
+        Ok(RedbBackend {
+            db: Arc::new(redb::Database::create(path).map_err(VaultError::storage)?),
+            watches: Arc::default(),
+        })
+    }
+}
+
+/// A table of a redb database.
+pub struct RedbTree {
+    db: Arc<redb::Database>,
+    name: String,
+    watches: Watches,
+}
+
+impl RedbTree {
+    fn definition(&self) -> Definition<'_> {
+        TableDefinition::new(&self.name)
+    }
+
+    // Runs `f` on the table in a write transaction of its own.
+    fn write<R>(&self, f: impl FnOnce(&mut redb::Table<&'static [u8], &'static [u8]>) -> Result<R, VaultError>) -> Result<R, VaultError> {
+        let tx = self.db.begin_write().map_err(VaultError::storage)?;
+        let result = f(&mut tx.open_table(self.definition()).map_err(VaultError::storage)?)?;
+        tx.commit().map_err(VaultError::storage)?;
+        Ok(result)
+    }
+
+    // The table as of now, or `None` if nothing was ever written to it.
+    fn read(&self) -> Result<Option<ReadOnlyTable>, VaultError> {
+        let tx = self.db.begin_read().map_err(VaultError::storage)?;
+        match tx.open_table(self.definition()) {
+            Ok(table) => Ok(Some(table)),
+            Err(TableError::TableDoesNotExist(_)) => Ok(None),
+            Err(err) => Err(VaultError::storage(err)),
+        }
+    }
+}
+
+impl VaultBackend for RedbBackend {
+    type Tree = RedbTree;
+
+    fn open_tree(&self, name: &str) -> Result<RedbTree, VaultError> {
+        let watches = self.watches.lock().unwrap_or_else(PoisonError::into_inner).entry(name.to_owned()).or_default().clone();
+        Ok(RedbTree { db: self.db.clone(), name: name.to_owned(), watches })
+    }
+
+    fn transaction<R>(&self, trees: &[&RedbTree], f: &dyn Fn(&dyn BackendTransaction) -> Result<R, VaultError>) -> Result<R, VaultError> {
+        let tx = self.db.begin_write().map_err(VaultError::storage)?;
+        let result = {
+            let tables = trees.iter()
+                .map(|tree| tx.open_table(tree.definition()).map(RefCell::new))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(VaultError::storage)?;
+            let redb_tx = RedbTransaction { tx: &tx, tables, inserted: RefCell::default() };
+            f(&redb_tx).map(|result| (result, redb_tx.inserted.into_inner()))
+        };
+        // Dropping the transaction without committing it aborts it.
+        let (result, inserted) = result?;
+        tx.commit().map_err(VaultError::storage)?;
+        for (tree, (key, value)) in inserted {
+            trees[tree].watches.inserted(&key, &value);
+        }
+        Ok(result)
+    }
+
+    fn generate_id(&self) -> Result<u64, VaultError> {
+        Ok(self.generate_ids(1)?[0])
+    }
+
+    fn generate_ids(&self, count: usize) -> Result<Vec<u64>, VaultError> {
+        let tx = self.db.begin_write().map_err(VaultError::storage)?;
+        let first = next_ids(&tx, count as u64)?;
+        tx.commit().map_err(VaultError::storage)?;
+        Ok((first..first + count as u64).collect())
+    }
+}
+
+impl VaultTree for RedbTree {
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, VaultError> {
+        let Some(table) = self.read()? else { return Ok(None) };
+        Ok(table.get(key.as_ref()).map_err(VaultError::storage)?.map(|value| value.value().to_vec()))
+    }
+
+    fn insert(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), VaultError> {
+        self.write(|table| {
+            table.insert(key.as_ref(), value.as_ref()).map_err(VaultError::storage)?;
+            Ok(())
+        })?;
+        self.watches.inserted(key.as_ref(), value.as_ref());
+        Ok(())
+    }
+
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<(), VaultError> {
+        self.write(|table| {
+            table.remove(key.as_ref()).map_err(VaultError::storage)?;
+            Ok(())
+        })
+    }
+
+    fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Entries<'_> {
+        if is_empty_range(&range) {
+            return Box::new(std::iter::empty());
+        }
+        let entries = || {
+            let Some(table) = self.read()? else { return Ok(None) };
+            let bounds = (range.0.as_ref().map(Vec::as_slice), range.1.as_ref().map(Vec::as_slice));
+            table.range::<&[u8]>(bounds).map(Some).map_err(VaultError::storage)
+        };
+        match entries() {
+            Ok(Some(entries)) => Box::new(entries.map(|entry| {
+                let (key, value) = entry.map_err(VaultError::storage)?;
+                Ok((key.value().to_vec(), value.value().to_vec()))
+            })),
+            Ok(None) => Box::new(std::iter::empty()),
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        }
+    }
+
+    fn clear(&self) -> Result<(), VaultError> {
+        self.write(|table| table.retain(|_, _| false).map_err(VaultError::storage))
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), VaultError> {
+        self.write(|table| {
+            for (key, value) in batch.writes() {
+                match value {
+                    Some(value) => table.insert(key, value).map(|_| ()),
+                    None => table.remove(key).map(|_| ()),
+                }
+                .map_err(VaultError::storage)?;
+            }
+            Ok(())
+        })?;
+        for (key, value) in batch.writes() {
+            if let Some(value) = value {
+                self.watches.inserted(key, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn watch_prefix(&self, prefix: &[u8]) -> Result<Box<dyn PrefixWatch>, VaultError> {
+        Ok(self.watches.watch(prefix))
+    }
+}
+
+struct RedbTransaction<'a> {
+    tx: &'a redb::WriteTransaction,
+    tables: Vec<RefCell<redb::Table<'a, &'static [u8], &'static [u8]>>>,
+    // The entries inserted so far, by the position of their table, for the watches on the tables.
+    inserted: RefCell<Vec<(usize, Entry)>>,
+}
+
+impl BackendTransaction for RedbTransaction<'_> {
+    fn get(&self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, VaultError> {
+        let table = self.tables[tree].borrow();
+        let value = table.get(key).map_err(VaultError::storage)?.map(|value| value.value().to_vec());
+        Ok(value)
+    }
+
+    fn insert(&self, tree: usize, key: &[u8], value: &[u8]) -> Result<(), VaultError> {
+        self.tables[tree].borrow_mut().insert(key, value).map_err(VaultError::storage)?;
+        self.inserted.borrow_mut().push((tree, (key.to_vec(), value.to_vec())));
+        Ok(())
+    }
+
+    fn remove(&self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, VaultError> {
+        let mut table = self.tables[tree].borrow_mut();
+        let removed = table.remove(key).map_err(VaultError::storage)?.map(|value| value.value().to_vec());
+        Ok(removed)
+    }
+
+    // Counted within the transaction, so numbers handed out by a transaction that doesn't go
+    // through are handed out again.
+    fn generate_id(&self) -> Result<u64, VaultError> {
+        next_ids(self.tx, 1)
+    }
+}
+
+// Counts up the number that `generate_id` hands out next by `count`, returning the first of the
+// numbers counted past.
+fn next_ids(tx: &redb::WriteTransaction, count: u64) -> Result<u64, VaultError> {
+    let mut ids = tx.open_table(IDS_TABLE).map_err(VaultError::storage)?;
+    let next = match ids.get(&[][..]).map_err(VaultError::storage)? {
+        Some(bytes) => u64::from_be_bytes(bytes.value().try_into().map_err(|_| VaultError::Corrupt("generated id".to_owned()))?),
+        None => 0,
+    };
+    ids.insert(&[][..], &(next + count).to_be_bytes()[..]).map_err(VaultError::storage)?;
+    Ok(next)
+}
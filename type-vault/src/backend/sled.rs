@@ -0,0 +1,151 @@
+use super::{is_empty_range, Batch, BackendTransaction, Entries, Entry, PrefixWatch, VaultBackend, VaultTree};
+use type_vault_trait::VaultError;
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional, TransactionalTree, UnabortableTransactionError};
+use std::{ops::Bound, time::{Duration, Instant}};
+
+/// Keeps the trees of a vault in a sled database, the default backend. Clones share the database.
+#[derive(Clone)]
+pub struct SledBackend {
+    db: sled::Db,
+}
+
+impl SledBackend {
+    pub fn open(path: &std::path::Path) -> Result<Self, VaultError> {
+        Self::with_config(sled::Config::new().path(path))
+    }
+
+    /// Opens the sled database that `config` describes.
+    pub fn with_config(config: sled::Config) -> Result<Self, VaultError> {
+        Ok(SledBackend { db: config.open().map_err(VaultError::storage)? })
+    }
+}
+
+impl VaultBackend for SledBackend {
+    type Tree = sled::Tree;
+
+    fn open_tree(&self, name: &str) -> Result<sled::Tree, VaultError> {
+        self.db.open_tree(name).map_err(VaultError::storage)
+    }
+
+    fn transaction<R>(&self, trees: &[&sled::Tree], f: &dyn Fn(&dyn BackendTransaction) -> Result<R, VaultError>) -> Result<R, VaultError> {
+        trees
+            .transaction(|trees| f(&SledTransaction { trees }).map_err(into_conflictable))
+            .map_err(|err| match err {
+                TransactionError::Abort(err) => err,
+                TransactionError::Storage(err) => VaultError::storage(err),
+            })
+    }
+
+    fn generate_id(&self) -> Result<u64, VaultError> {
+        self.db.generate_id().map_err(VaultError::storage)
+    }
+
+    fn flush(&self) -> Result<(), VaultError> {
+        self.db.flush().map_err(VaultError::storage)?;
+        Ok(())
+    }
+
+    async fn flush_async(&self) -> Result<(), VaultError> {
+        self.db.flush_async().await.map_err(VaultError::storage)?;
+        Ok(())
+    }
+}
+
+impl VaultTree for sled::Tree {
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, VaultError> {
+        Ok(sled::Tree::get(self, key).map_err(VaultError::storage)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), VaultError> {
+        sled::Tree::insert(self, key.as_ref(), value.as_ref()).map_err(VaultError::storage)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<(), VaultError> {
+        sled::Tree::remove(self, key).map_err(VaultError::storage)?;
+        Ok(())
+    }
+
+    fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Entries<'_> {
+        if is_empty_range(&range) {
+            return Box::new(std::iter::empty());
+        }
+        Box::new(sled::Tree::range(self, range).map(|entry| {
+            let (key, value) = entry.map_err(VaultError::storage)?;
+            Ok((key.to_vec(), value.to_vec()))
+        }))
+    }
+
+    fn clear(&self) -> Result<(), VaultError> {
+        sled::Tree::clear(self).map_err(VaultError::storage)
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), VaultError> {
+        let mut sled_batch = sled::Batch::default();
+        for (key, value) in batch.writes() {
+            match value {
+                Some(value) => sled_batch.insert(key, value),
+                None => sled_batch.remove(key),
+            }
+        }
+        sled::Tree::apply_batch(self, sled_batch).map_err(VaultError::storage)
+    }
+
+    fn watch_prefix(&self, prefix: &[u8]) -> Result<Box<dyn PrefixWatch>, VaultError> {
+        Ok(Box::new(sled::Tree::watch_prefix(self, prefix)))
+    }
+
+    fn contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool, VaultError> {
+        sled::Tree::contains_key(self, key).map_err(VaultError::storage)
+    }
+}
+
+impl PrefixWatch for sled::Subscriber {
+    fn next_insert(&mut self, timeout: Option<Duration>) -> Option<Entry> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let event = match deadline {
+                Some(deadline) => self.next_timeout(deadline.checked_duration_since(Instant::now())?).ok()?,
+                None => self.next()?,
+            };
+            if let sled::Event::Insert { key, value } = event {
+                return Some((key.to_vec(), value.to_vec()));
+            }
+        }
+    }
+}
+
+struct SledTransaction<'a> {
+    trees: &'a [TransactionalTree],
+}
+
+impl BackendTransaction for SledTransaction<'_> {
+    fn get(&self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, VaultError> {
+        Ok(self.trees[tree].get(key).map_err(VaultError::storage)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, tree: usize, key: &[u8], value: &[u8]) -> Result<(), VaultError> {
+        self.trees[tree].insert(key, value).map_err(VaultError::storage)?;
+        Ok(())
+    }
+
+    fn remove(&self, tree: usize, key: &[u8]) -> Result<Option<Vec<u8>>, VaultError> {
+        Ok(self.trees[tree].remove(key).map_err(VaultError::storage)?.map(|value| value.to_vec()))
+    }
+
+    fn generate_id(&self) -> Result<u64, VaultError> {
+        self.trees[0].generate_id().map_err(VaultError::storage)
+    }
+}
+
+// Storage errors raised inside a transaction may be conflicts that sled resolves by rerunning the
+// transaction, so they have to be handed back to sled unchanged instead of aborting.
+fn into_conflictable(err: VaultError) -> ConflictableTransactionError<VaultError> {
+    match err {
+        VaultError::Storage(inner) => match inner.downcast::<UnabortableTransactionError>() {
+            Ok(inner) => (*inner).into(),
+            Err(inner) => ConflictableTransactionError::Abort(VaultError::Storage(inner)),
+        },
+        err => ConflictableTransactionError::Abort(err),
+    }
+}
@@ -0,0 +1,216 @@
+//! Storing many values at once. Values are collected in memory, and every few thousand of them
+//! are written with a single batch per tree instead of a transaction each. Values that occur more
+//! than once, on their own or nested in others, are only written once, and values that are
+//! already stored are not written at all.
+
+use crate::{child_ids, index_keys, own_type, unstored, Batch, TypeVault, VaultBackend, VaultTree, CHANGE_LOG_KEY, REFCOUNTS_KEY};
+use type_vault_trait::*;
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+const DEFAULT_BATCH_SIZE: usize = 10_000;
+
+/// What a `BulkWriter` stored, see `TypeVault::put_many`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BulkReport {
+    /// The number of values that were put, not counting the values nested in them.
+    pub values: usize,
+    /// The number of values that were written, nested ones included.
+    pub written: usize,
+    /// The number of values that were skipped because they were put earlier on in the same batch.
+    pub duplicates: usize,
+    /// The number of values that were skipped because they were stored already. The values nested
+    /// in skipped values are not looked at, so they are not counted either.
+    pub already_stored: usize,
+    /// The size of the values that were written, with their ids.
+    pub bytes_written: u64,
+    /// The time spent serializing and writing the values.
+    pub elapsed: Duration,
+}
+
+impl BulkReport {
+    /// The number of values put per second, not counting the values nested in them.
+    /// Nothing was put if no time was spent, which counts as none per second.
+    pub fn values_per_second(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.values as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Collects values to store, and writes them in batches, see `TypeVault::bulk_writer`.
+///
+/// The values in a batch are written one tree at a time, so readers may see a value before it
+/// is indexed, and a crash may leave part of a batch behind. The reference counts and the change
+/// log are marked as out of date while a batch is written, so that they are put right the next
+/// time the vault is opened if it doesn't finish. Nothing else should write to the vault
+/// meanwhile.
+pub struct BulkWriter<'a, B: VaultBackend> {
+    vault: &'a TypeVault<B>,
+    batch_size: usize,
+    // The values to write in the next batch, in the order they were put, nested ones first.
+    pending: Vec<(Vec<u8>, ValueId)>,
+    // The position of every value in `pending`, by its id.
+    positions: HashMap<ValueId, usize>,
+    report: BulkReport,
+}
+
+impl<B: VaultBackend> BulkWriter<'_, B> {
+    /// How many values to collect before writing them, nested ones included. Defaults to 10000.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Puts a value with all of its nested values into the next batch, and writes the batch if it
+    /// is full. Returns the id of the value, which may not be stored yet.
+    pub fn put<T: VaultType>(&mut self, value: &T) -> Result<ValueId, VaultError> {
+        let start = Instant::now();
+        self.vault.check_writable()?;
+        // Values that are pending are stored with everything nested in them by the time the
+        // batch is written, like the ones that are stored already.
+        let is_stored = |id| Ok(self.positions.contains_key(&id) || self.vault.id_to_value_map.get(id)?.is_some());
+        let data = serialize_type_skipping(value, &self.vault.type_map, &is_stored)?;
+        let root_id = data.last().map(|(_val, id)| *id)
+            .expect("serialize_type always returns the value itself");
+        let Self { vault, pending, positions, report, .. } = self;
+        let unstored = unstored(&vault.type_map, data, |id| match positions.get(&id) {
+            Some(position) => {
+                report.duplicates += 1;
+                Ok(Some(pending[*position].0.clone()))
+            },
+            None => {
+                let stored = vault.id_to_value_map.get(id)?;
+                report.already_stored += usize::from(stored.is_some());
+                Ok(stored)
+            },
+        })?;
+        for (val, id) in unstored {
+            self.positions.insert(id, self.pending.len());
+            self.pending.push((val, id));
+        }
+        self.report.values += 1;
+        self.report.elapsed += start.elapsed();
+        if self.pending.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(root_id)
+    }
+
+    /// Writes the values collected so far. If that fails they are kept, to be written by the
+    /// next `flush`.
+    pub fn flush(&mut self) -> Result<(), VaultError> {
+        let start = Instant::now();
+        let result = self.write();
+        if result.is_ok() {
+            self.pending.clear();
+            self.positions.clear();
+        }
+        self.report.elapsed += start.elapsed();
+        result
+    }
+
+    /// Writes the values collected so far, and reports on everything written.
+    pub fn finish(mut self) -> Result<BulkReport, VaultError> {
+        self.flush()?;
+        Ok(self.report)
+    }
+
+    // Writes the values in `pending` with their index, referrers, reference counts and change log
+    // entries. The values themselves come last but for their change log entries, as everything
+    // else is ignored for values that are not stored, or put right by counting the references
+    // again. The change log entries come after the values, so that subscriptions find the values
+    // they are told about, and are written on opening the vault again if they aren't.
+    fn write(&mut self) -> Result<(), VaultError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let vault = self.vault;
+        let [mut index, mut referrers, mut refcounts, mut change_log, mut change_log_keys, mut value_to_id, mut id_to_value]: [Batch; 7] = Default::default();
+        let mut references: HashMap<ValueId, u64> = HashMap::new();
+        // Values that are not stored under a tag of their own are not logged.
+        let mut is_logged = Vec::with_capacity(self.pending.len());
+        for (val, _id) in &self.pending {
+            is_logged.push(own_type(&vault.type_map, val)?.is_some());
+        }
+        // The sequence numbers of the whole batch are generated at once, as that may be a write.
+        let mut sequences = vault.backend.generate_ids(is_logged.iter().filter(|is_logged| **is_logged).count())?.into_iter();
+        for ((val, id), is_logged) in self.pending.iter().zip(is_logged) {
+            for key in index_keys(&vault.type_map, val, *id)? {
+                index.insert(key, []);
+            }
+            for child in child_ids(&vault.type_map, val)? {
+                referrers.insert([child.as_bytes(), id.as_bytes()].concat(), []);
+                *references.entry(child).or_default() += 1;
+            }
+            if is_logged {
+                let (_, tag_len) = decode_tag(val)?;
+                let sequence = sequences.next().expect("a sequence number is generated for every logged value");
+                let key = [&val[..tag_len], &sequence.to_be_bytes()].concat();
+                change_log.insert(key.clone(), id.as_bytes());
+                change_log_keys.insert(id.as_bytes(), key);
+            }
+            value_to_id.insert(val.as_slice(), id.as_bytes());
+            id_to_value.insert(id.as_bytes(), val.as_slice());
+            self.report.written += 1;
+            self.report.bytes_written += 2 * (id.as_bytes().len() + val.len()) as u64;
+        }
+        // Without reference counts, see `VaultOptions::reference_counts`, none are written.
+        if !vault.reference_counts {
+            references.clear();
+        }
+        for (id, count) in references {
+            let refcount: u64 = match vault.refcounts_map.get(id)? {
+                Some(bytes) => bincode::serde::decode_from_slice(&bytes, BINCODE_CONFIG)?.0,
+                None => 0,
+            };
+            refcounts.insert(id.as_bytes(), bincode::serde::encode_to_vec(refcount + count, BINCODE_CONFIG)?);
+        }
+        // The counts and the change log are only marked complete again if they were before.
+        let has_refcounts = vault.meta_map.get(REFCOUNTS_KEY)?.is_some();
+        let has_change_log = vault.meta_map.get(CHANGE_LOG_KEY)?.is_some();
+        vault.meta_map.remove(REFCOUNTS_KEY)?;
+        vault.meta_map.remove(CHANGE_LOG_KEY)?;
+        vault.index_map.apply_batch(index)?;
+        vault.referrers_map.apply_batch(referrers)?;
+        vault.change_log_keys_map.apply_batch(change_log_keys)?;
+        vault.refcounts_map.apply_batch(refcounts)?;
+        vault.value_to_id_map.apply_batch(value_to_id)?;
+        vault.id_to_value_map.apply_batch(id_to_value)?;
+        vault.change_log_map.apply_batch(change_log)?;
+        if has_change_log {
+            vault.meta_map.insert(CHANGE_LOG_KEY, [])?;
+        }
+        if has_refcounts {
+            vault.meta_map.insert(REFCOUNTS_KEY, [])?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: VaultBackend> Drop for BulkWriter<'_, B> {
+    // Errors can't be reported here, `finish` reports them.
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl<B: VaultBackend> TypeVault<B> {
+    /// A writer for storing many values at once, see `BulkWriter`.
+    pub fn bulk_writer(&self) -> BulkWriter<'_, B> {
+        BulkWriter {
+            vault: self,
+            batch_size: DEFAULT_BATCH_SIZE,
+            pending: vec![],
+            positions: HashMap::new(),
+            report: BulkReport::default(),
+        }
+    }
+
+    /// Stores all of `values` through a `BulkWriter`, returning their ids in the same order.
+    pub fn put_many<'v, T: VaultType + 'v>(&self, values: impl IntoIterator<Item = &'v T>) -> Result<(Vec<ValueId>, BulkReport), VaultError> {
+        let mut writer = self.bulk_writer();
+        let ids = values.into_iter().map(|value| writer.put(value)).collect::<Result<_, _>>()?;
+        Ok((ids, writer.finish()?))
+    }
+}
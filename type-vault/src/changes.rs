@@ -0,0 +1,98 @@
+//! The change log: every value stored through `put`, a transaction or a `BulkWriter`, or converted
+//! by a migration, gets an entry under the tag of its type with an increasing sequence number, so
+//! consumers can read the values of a type in the order they were stored, and resume after the
+//! last sequence number they processed. Entries are removed together with their values.
+
+use crate::{id_from_bytes, prefix_end, PrefixWatch, SledBackend, TypeVault, VaultBackend, VaultTree};
+use type_vault_trait::*;
+use std::{marker::PhantomData, ops::Bound, time::Duration};
+
+/// A value of type `T` that was stored, with its position in the change log.
+#[derive(Debug, PartialEq)]
+pub struct Change<T> {
+    pub sequence: u64,
+    pub id: ValueId,
+    pub value: Box<T>,
+}
+
+/// Waits for values of type `T` to be stored, see `TypeVault::subscribe`. Iterating blocks until
+/// the next one is stored.
+pub struct Subscription<'a, T, B: VaultBackend = SledBackend> {
+    vault: &'a TypeVault<B>,
+    // The change log entries of the type, as they are written.
+    entries: Box<dyn PrefixWatch>,
+    _type: PhantomData<T>,
+}
+
+impl<T: VaultType, B: VaultBackend> Subscription<'_, T, B> {
+    /// Waits for the next value for at most `timeout`, returning `None` if none was stored.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<Result<Change<T>, VaultError>> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let timeout = deadline.checked_duration_since(std::time::Instant::now())?;
+            let (key, id_bytes) = self.entries.next_insert(Some(timeout))?;
+            // The value may have been removed since.
+            if let Some(change) = self.vault.change(&key, &id_bytes).transpose() {
+                return Some(change);
+            }
+        }
+    }
+}
+
+impl<T: VaultType, B: VaultBackend> Iterator for Subscription<'_, T, B> {
+    type Item = Result<Change<T>, VaultError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, id_bytes) = self.entries.next_insert(None)?;
+            if let Some(change) = self.vault.change(&key, &id_bytes).transpose() {
+                return Some(change);
+            }
+        }
+    }
+}
+
+impl<B: VaultBackend> TypeVault<B> {
+    /// Yields every value of type `T` that is stored from now on, as it is stored. Values that
+    /// were already stored are not stored again by another put, so they are not yielded.
+    ///
+    /// Subscriptions watch the change log of the type, see `VaultTree::watch_prefix`, so they see
+    /// what every handle on the backend stores, whether it puts the values, writes them in bulk
+    /// or converts them in a migration. Values that are stored together may be yielded in any
+    /// order, their `Change::sequence` tells the order they were logged in.
+    pub fn subscribe<T: VaultType + 'static>(&self) -> Result<Subscription<'_, T, B>, VaultError> {
+        let tag = self.type_map.tag_of::<T>()?;
+        let entries = self.change_log_map.watch_prefix(&tag)?;
+        Ok(Subscription { vault: self, entries, _type: PhantomData })
+    }
+
+    /// The values of type `T` in the order they were stored, starting at the sequence number
+    /// `first`. Values converted by a migration are logged anew when they are converted.
+    #[allow(clippy::type_complexity)]
+    pub fn changes_from<'a, T: VaultType + 'static>(&'a self, first: u64) -> Result<impl Iterator<Item = Result<Change<T>, VaultError>> + 'a, VaultError> {
+        let tag = self.type_map.tag_of::<T>()?;
+        let start = [tag.as_slice(), &first.to_be_bytes()].concat();
+        Ok(self.change_log_map.range((Bound::Included(start), Bound::Excluded(prefix_end(&tag)))).filter_map(move |entry| {
+            let change = || {
+                let (key, id_bytes) = entry?;
+                self.change(&key, &id_bytes)
+            };
+            change().transpose()
+        }))
+    }
+
+    // Reads the value of a change log entry, if it is still stored.
+    fn change<T: VaultType>(&self, key: &[u8], id_bytes: &[u8]) -> Result<Option<Change<T>>, VaultError> {
+        let sequence_bytes = key.len().checked_sub(8).map(|start| &key[start..])
+            .ok_or_else(|| VaultError::Corrupt(format!("change log key {:?} is too short", key)))?;
+        let sequence = u64::from_be_bytes(sequence_bytes.try_into().expect("the slice has 8 bytes"));
+        let id = id_from_bytes(id_bytes)?;
+        match self.lookup_id(id)? {
+            Some(data) => {
+                let value = deserialize_type::<T>(&data, &self.type_map, &|id_needle| self.lookup_id(id_needle))?;
+                Ok(Some(Change { sequence, id, value: Box::new(value) }))
+            },
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! Rules that derive new facts from the stored ones, in the style of Datalog. A rule derives a
+//! value of one struct type from stored values of others, with a term for each of their fields,
+//! and variables that occur in several places join them on equal fields. Fields stored by id join
+//! with the ids of the values they refer to, see `Rule::when_id`.
+
+use crate::{own_type, Resolved, TypeVault, VaultBackend, VaultTree};
+use type_vault_trait::*;
+use std::{collections::HashMap, iter::once};
+
+type InfoOf = for<'m> fn(&'m TypeMap) -> Result<&'m TypeInfo, VaultError>;
+
+/// A field in a rule: a variable, a fixed value, or any value. Strings are variables, except for
+/// `"_"`, which is any value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Term {
+    Var(String),
+    /// The encoding of a fixed value, and its type if it is stored inline.
+    Value { encoding: Vec<u8>, ty: Option<String> },
+    Any,
+}
+
+impl Term {
+    /// A fixed value of a field that is stored inline.
+    pub fn value<V: OrderedKey>(value: &V) -> Term {
+        Term::Value {
+            encoding: encode_key(value).expect("primitive values always encode"),
+            ty: Some(std::any::type_name::<V>().split_whitespace().collect()),
+        }
+    }
+
+    /// A fixed id, of a value or of what a field refers to. In the head of a rule it has to be
+    /// the id of a stored value, as derived values can only refer to those.
+    pub fn id(id: ValueId) -> Term {
+        Term::Value {
+            encoding: bincode::serde::encode_to_vec(id, BINCODE_CONFIG).expect("ids always encode"),
+            ty: None,
+        }
+    }
+}
+
+impl From<&str> for Term {
+    fn from(name: &str) -> Self {
+        match name {
+            "_" => Term::Any,
+            name => Term::Var(name.to_owned()),
+        }
+    }
+}
+
+struct Atom {
+    info: InfoOf,
+    type_name: &'static str,
+    id: Term,
+    terms: Vec<Term>,
+}
+
+impl Atom {
+    fn of<T: VaultType + 'static>(id: Term, terms: impl IntoIterator<Item = impl Into<Term>>) -> Atom {
+        Atom { info: TypeMap::info_of::<T>, type_name: T::TYPE_NAME, id, terms: terms.into_iter().map(Into::into).collect() }
+    }
+}
+
+/// Derives a value of one type for every combination of stored values that match its body.
+pub struct Rule {
+    head: Atom,
+    body: Vec<Atom>,
+}
+
+impl Rule {
+    /// A rule that derives values of `T`, with a term for each of its fields in declaration order.
+    /// Every variable in it has to occur in the body of the rule.
+    pub fn derive<T: VaultType + 'static>(terms: impl IntoIterator<Item = impl Into<Term>>) -> Rule {
+        Rule { head: Atom::of::<T>(Term::Any, terms), body: vec![] }
+    }
+
+    /// Requires a stored value of `T` whose fields match `terms`, in declaration order.
+    pub fn when<T: VaultType + 'static>(self, terms: impl IntoIterator<Item = impl Into<Term>>) -> Rule {
+        self.when_id::<T>(Term::Any, terms)
+    }
+
+    /// Like `when`, but also matches the id of the value with `id`. A variable used for both the
+    /// id of a value and a field of another joins the value with those that refer to it.
+    pub fn when_id<T: VaultType + 'static>(mut self, id: impl Into<Term>, terms: impl IntoIterator<Item = impl Into<Term>>) -> Rule {
+        self.body.push(Atom::of::<T>(id.into(), terms));
+        self
+    }
+}
+
+/// The rules that `TypeVault::evaluate` derives facts with.
+#[derive(Default)]
+pub struct Program {
+    rules: Vec<Rule>,
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+}
+
+// A term with its variable replaced by its position in the bindings of the rule.
+enum Slot {
+    Any,
+    Value(Vec<u8>),
+    Var(usize),
+}
+
+struct CompiledAtom<'m> {
+    info: &'m TypeInfo,
+    tag: Vec<u8>,
+    id: Slot,
+    fields: Vec<Slot>,
+}
+
+struct CompiledRule<'m> {
+    head: CompiledAtom<'m>,
+    body: Vec<CompiledAtom<'m>>,
+    vars: usize,
+}
+
+// The encodings of the fields of a fact and of its id.
+#[derive(Clone)]
+struct Fact {
+    fields: Vec<Vec<u8>>,
+    id: Vec<u8>,
+}
+
+type Bindings = Vec<Option<Vec<u8>>>;
+
+// A derived fact together with the tag of its type and its stored data.
+struct Derived {
+    tag: Vec<u8>,
+    data: Vec<u8>,
+    fact: Fact,
+}
+
+// Numbers the variables of a rule, and checks that it can be evaluated: that every atom has a
+// term for each field, that variables are only used for fields of the same type, and that the
+// head is fully determined by the body.
+fn compile<'m>(rule: &'m Rule, type_map: &'m TypeMap) -> Result<CompiledRule<'m>, VaultError> {
+    let mut vars: HashMap<&str, (usize, String, FieldStorage)> = HashMap::new();
+    let mut compile_atom = |atom: &'m Atom, is_head: bool| -> Result<CompiledAtom<'m>, VaultError> {
+        let info = (atom.info)(type_map)?;
+        let tag = type_map.get(&info.type_id).ok_or(VaultError::UnregisteredType(info.name))?;
+        let Schema::Struct(fields) = &info.schema else {
+            return Err(VaultError::InvalidRule(format!("{} is not a struct", atom.type_name)));
+        };
+        if fields.len() != atom.terms.len() {
+            return Err(VaultError::InvalidRule(format!("{} has {} fields but is given {} terms", atom.type_name, fields.len(), atom.terms.len())));
+        }
+        let mut compile_term = |term: &'m Term, ty: &str, storage: FieldStorage| -> Result<Slot, VaultError> {
+            match term {
+                Term::Any if is_head => Err(VaultError::InvalidRule(format!("the head {} has a field with any value", atom.type_name))),
+                Term::Any => Ok(Slot::Any),
+                Term::Value { encoding, ty: value_ty } => match value_ty {
+                    Some(value_ty) if storage != FieldStorage::Inline || value_ty != ty =>
+                        Err(VaultError::InvalidRule(format!("a value of type {} is given for a field of type {} of {}", value_ty, ty, atom.type_name))),
+                    _ => Ok(Slot::Value(encoding.clone())),
+                },
+                Term::Var(name) => match vars.get(name.as_str()) {
+                    Some((_, var_ty, var_storage)) if (var_ty.as_str(), *var_storage) != (ty, storage) =>
+                        Err(VaultError::InvalidRule(format!("variable {} is used for fields of type {} and {}", name, var_ty, ty))),
+                    Some((index, _, _)) => Ok(Slot::Var(*index)),
+                    None if is_head =>
+                        Err(VaultError::InvalidRule(format!("variable {} of the head {} does not occur in the body", name, atom.type_name))),
+                    None => {
+                        let index = vars.len();
+                        vars.insert(name, (index, ty.to_owned(), storage));
+                        Ok(Slot::Var(index))
+                    },
+                },
+            }
+        };
+        // The id of a derived value follows from its fields.
+        let id = if is_head { Slot::Any } else { compile_term(&atom.id, info.name, FieldStorage::ById)? };
+        let fields = std::iter::zip(&atom.terms, fields)
+            .map(|(term, field)| compile_term(term, &field.ty, field.storage))
+            .collect::<Result<_, _>>()?;
+        Ok(CompiledAtom { info, tag, id, fields })
+    };
+    let body = rule.body.iter().map(|atom| compile_atom(atom, false)).collect::<Result<_, _>>()?;
+    let head = compile_atom(&rule.head, true)?;
+    Ok(CompiledRule { head, body, vars: vars.len() })
+}
+
+impl<B: VaultBackend> TypeVault<B> {
+    /// Derives facts with the rules of `program` until they derive nothing new, and stores them
+    /// like `put` does. Rules are evaluated semi-naively: after a first round over everything that
+    /// is stored, each round only joins the facts derived in the round before with the stored
+    /// ones, which are looked up through the same prefixes and indexes as queries. Returns the
+    /// number of facts that were derived.
+    pub fn evaluate(&self, program: &Program) -> Result<usize, VaultError> {
+        let rules = program.rules.iter().map(|rule| compile(rule, &self.type_map)).collect::<Result<Vec<_>, _>>()?;
+        for rule in &rules {
+            self.check_head_ids(&rule.head)?;
+        }
+        let mut derived = 0;
+        // The facts derived in the last round, by the tag of their type.
+        let mut delta: Option<HashMap<Vec<u8>, Vec<Fact>>> = None;
+        loop {
+            let mut new_facts = HashMap::new();
+            for rule in &rules {
+                match &delta {
+                    None => self.derive(rule, None, &mut new_facts)?,
+                    Some(delta) => {
+                        for (index, atom) in rule.body.iter().enumerate() {
+                            if let Some(facts) = delta.get(&atom.tag) {
+                                self.derive(rule, Some((index, facts)), &mut new_facts)?;
+                            }
+                        }
+                    },
+                }
+            }
+            let mut stored = vec![];
+            for id in new_facts.keys() {
+                if self.id_to_value_map.contains_key(id)? {
+                    stored.push(*id);
+                }
+            }
+            stored.iter().for_each(|id| { new_facts.remove(id); });
+            if new_facts.is_empty() {
+                return Ok(derived);
+            }
+            self.transaction(|tx| {
+                for (id, new_fact) in &new_facts {
+                    tx.store(new_fact.data.clone(), *id)?;
+                }
+                Ok(())
+            })?;
+            derived += new_facts.len();
+            let mut next_delta: HashMap<Vec<u8>, Vec<Fact>> = HashMap::new();
+            for new_fact in new_facts.into_values() {
+                next_delta.entry(new_fact.tag).or_default().push(new_fact.fact);
+            }
+            delta = Some(next_delta);
+        }
+    }
+
+    // Rejects a head that fixes a field stored by id to an id that no stored value has.
+    fn check_head_ids(&self, head: &CompiledAtom) -> Result<(), VaultError> {
+        let Schema::Struct(fields) = &head.info.schema else {
+            unreachable!("compiled atoms are structs")
+        };
+        for (slot, field) in std::iter::zip(&head.fields, fields) {
+            if let (Slot::Value(encoding), FieldStorage::ById) = (slot, field.storage) {
+                let (id, _): (ValueId, _) = bincode::serde::decode_from_slice(encoding, BINCODE_CONFIG)?;
+                if !self.id_to_value_map.contains_key(id)? {
+                    return Err(VaultError::InvalidRule(format!("the head {} refers to {}, which is not stored", head.info.name, id)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Evaluates `rule`, with the body atom at `delta.0` matched against the facts `delta.1`
+    // instead of the stored ones, and collects what it derives.
+    fn derive(&self, rule: &CompiledRule, delta: Option<(usize, &[Fact])>, derived: &mut HashMap<ValueId, Derived>) -> Result<(), VaultError> {
+        // The atom matched against the new facts goes first, as there are fewer of them.
+        let mut order: Vec<usize> = (0..rule.body.len()).collect();
+        if let Some((first, _)) = delta {
+            order.retain(|index| *index != first);
+            order.insert(0, first);
+        }
+        self.join(rule, &order, delta, &vec![None; rule.vars], derived)
+    }
+
+    fn join(&self, rule: &CompiledRule, order: &[usize], delta: Option<(usize, &[Fact])>, bindings: &Bindings, derived: &mut HashMap<ValueId, Derived>) -> Result<(), VaultError> {
+        let Some((&index, rest)) = order.split_first() else {
+            let head = &rule.head;
+            let fields: Vec<Vec<u8>> = head.fields.iter().map(|slot| match slot {
+                Slot::Value(value) => value.clone(),
+                Slot::Var(var) => bindings[*var].clone().expect("variables of the head are bound by the body"),
+                Slot::Any => unreachable!("the head has no fields with any value"),
+            }).collect();
+            let data = [head.tag.as_slice(), &fields.concat()].concat();
+            let id = self.type_map.value_id_of(&data);
+            let fact = Fact { fields, id: bincode::serde::encode_to_vec(id, BINCODE_CONFIG)? };
+            derived.entry(id).or_insert(Derived { tag: head.tag.clone(), data, fact });
+            return Ok(());
+        };
+        let atom = &rule.body[index];
+        let stored;
+        let candidates = match delta {
+            Some((delta_index, facts)) if delta_index == index => facts,
+            _ => {
+                stored = self.matching_facts(atom, bindings)?;
+                stored.as_slice()
+            },
+        };
+        for fact in candidates {
+            let mut extended = bindings.clone();
+            if unify(atom, fact, &mut extended) {
+                self.join(rule, rest, delta, &extended, derived)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The stored facts of the type of `atom` whose fields have the values that are fixed or bound.
+    fn matching_facts(&self, atom: &CompiledAtom, bindings: &Bindings) -> Result<Vec<Fact>, VaultError> {
+        let value = |slot: &Slot| match slot {
+            Slot::Any => None,
+            Slot::Value(value) => Some(value.clone()),
+            Slot::Var(var) => bindings[*var].clone(),
+        };
+        let fact_of = |data: &[u8], id: ValueId| Ok(Fact {
+            fields: (atom.info.encoded_fields)(&data[atom.tag.len()..])?,
+            id: bincode::serde::encode_to_vec(id, BINCODE_CONFIG)?,
+        });
+        if let Some(id) = value(&atom.id) {
+            let (id, _): (ValueId, _) = bincode::serde::decode_from_slice(&id, BINCODE_CONFIG)?;
+            return match self.id_to_value_map.get(id)? {
+                Some(data) if own_type(&self.type_map, &data)?.is_some_and(|info| info.type_id == atom.info.type_id) =>
+                    Ok(vec![fact_of(&data, id)?]),
+                _ => Ok(vec![]),
+            };
+        }
+        let constraints = atom.fields.iter().map(|slot| value(slot).map_or(Resolved::Any, Resolved::Equals)).collect();
+        self.find(atom.info, constraints)?
+            .map(|candidate| {
+                let (data, id) = candidate?;
+                fact_of(&data, id)
+            })
+            .collect()
+    }
+}
+
+// Binds the unbound variables of `atom` to the fields of `fact`, if its other terms match them.
+fn unify(atom: &CompiledAtom, fact: &Fact, bindings: &mut Bindings) -> bool {
+    std::iter::zip(once(&atom.id).chain(&atom.fields), once(&fact.id).chain(&fact.fields)).all(|(slot, value)| match slot {
+        Slot::Any => true,
+        Slot::Value(expected) => expected == value,
+        Slot::Var(var) => match &bindings[*var] {
+            Some(bound) => bound == value,
+            None => {
+                bindings[*var] = Some(value.clone());
+                true
+            },
+        },
+    })
+}
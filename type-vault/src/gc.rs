@@ -0,0 +1,127 @@
+use crate::{child_ids, id_from_bytes, index_keys, Batch, TypeVault, VaultBackend, VaultTree, REFCOUNTS_KEY};
+use type_vault_trait::*;
+use std::collections::{HashMap, HashSet};
+
+// How many values are removed with each batch.
+const SWEEP_BATCH_SIZE: usize = 10_000;
+
+/// What `TypeVault::gc` found, and removed unless it was a dry run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// The number of stored values that none of the roots lead to.
+    pub orphans: usize,
+    /// The size of their ids and data, counted once for each direction they are stored in.
+    pub bytes_reclaimed: u64,
+}
+
+impl<B: VaultBackend> TypeVault<B> {
+    /// Removes every stored value that is not one of `roots` or nested in one of them, however
+    /// deeply. The values the named roots point at now are kept as well, but not those in their
+    /// history. With `dry_run`, only reports what would be removed. Fails with
+    /// `VaultError::MissingId` if one of `roots` is not stored, rather than removing everything.
+    ///
+    /// Unlike `remove`, this doesn't need reference counts, so it is the way to remove values
+    /// from vaults that don't keep them, see `VaultOptions::reference_counts`. Values are traced
+    /// from the roots first, and the rest is then removed in batches, one tree at a time, so
+    /// readers see the vault shrink gradually. Values put while it runs may refer to values it
+    /// removes, so nothing else should write meanwhile.
+    pub fn gc(&self, roots: impl IntoIterator<Item = ValueId>, dry_run: bool) -> Result<GcReport, VaultError> {
+        if !dry_run {
+            self.check_writable()?;
+        }
+        let mut roots: Vec<ValueId> = roots.into_iter().collect();
+        for root in self.roots() {
+            let (_, id) = root?;
+            // The value may have been removed since the root was pointed at it.
+            if self.id_to_value_map.contains_key(id)? {
+                roots.push(id);
+            }
+        }
+        let live = self.mark(roots)?;
+        let mut report = GcReport::default();
+        let mut orphans = vec![];
+        for entry in self.id_to_value_map.iter() {
+            let (id_bytes, data) = entry?;
+            let id = id_from_bytes(&id_bytes)?;
+            if live.contains(&id) {
+                continue;
+            }
+            report.orphans += 1;
+            report.bytes_reclaimed += 2 * (id_bytes.len() + data.len()) as u64;
+            orphans.push(id);
+        }
+        if !dry_run {
+            for batch in orphans.chunks(SWEEP_BATCH_SIZE) {
+                self.sweep(batch, &live)?;
+            }
+        }
+        Ok(report)
+    }
+
+    // Removes `orphans` with their index, referrers and change log entries, with a single batch
+    // per tree. The values go first, as readers skip whatever is left of the rest if it doesn't
+    // finish. Reference counts are marked as out of date meanwhile, like `BulkWriter` does.
+    fn sweep(&self, orphans: &[ValueId], live: &HashSet<ValueId>) -> Result<(), VaultError> {
+        let [mut id_to_value, mut value_to_id, mut index, mut referrers, mut change_log, mut change_log_keys, mut refcounts]: [Batch; 7] = Default::default();
+        // The references that orphans held to values that are kept.
+        let mut released: HashMap<ValueId, u64> = HashMap::new();
+        for id in orphans {
+            let Some(data) = self.id_to_value_map.get(id)? else { continue };
+            for key in index_keys(&self.type_map, &data, *id)? {
+                index.remove(key);
+            }
+            for child in child_ids(&self.type_map, &data)? {
+                referrers.remove([child.as_bytes(), id.as_bytes()].concat());
+                if live.contains(&child) {
+                    *released.entry(child).or_default() += 1;
+                }
+            }
+            if let Some(key) = self.change_log_keys_map.get(id)? {
+                change_log.remove(key);
+                change_log_keys.remove(id.as_bytes());
+            }
+            refcounts.remove(id.as_bytes());
+            value_to_id.remove(data);
+            id_to_value.remove(id.as_bytes());
+        }
+        if self.reference_counts {
+            for (id, count) in released {
+                let refcount: u64 = match self.refcounts_map.get(id)? {
+                    Some(bytes) => bincode::serde::decode_from_slice(&bytes, BINCODE_CONFIG)?.0,
+                    None => 0,
+                };
+                match refcount.saturating_sub(count) {
+                    0 => refcounts.remove(id.as_bytes()),
+                    refcount => refcounts.insert(id.as_bytes(), bincode::serde::encode_to_vec(refcount, BINCODE_CONFIG)?),
+                }
+            }
+        }
+        let has_refcounts = self.meta_map.get(REFCOUNTS_KEY)?.is_some();
+        self.meta_map.remove(REFCOUNTS_KEY)?;
+        self.id_to_value_map.apply_batch(id_to_value)?;
+        self.value_to_id_map.apply_batch(value_to_id)?;
+        self.index_map.apply_batch(index)?;
+        self.referrers_map.apply_batch(referrers)?;
+        self.change_log_map.apply_batch(change_log)?;
+        self.change_log_keys_map.apply_batch(change_log_keys)?;
+        self.refcounts_map.apply_batch(refcounts)?;
+        if has_refcounts {
+            self.meta_map.insert(REFCOUNTS_KEY, [])?;
+        }
+        Ok(())
+    }
+
+    // The ids of the roots and of everything nested in them.
+    fn mark(&self, roots: Vec<ValueId>) -> Result<HashSet<ValueId>, VaultError> {
+        let mut live = HashSet::new();
+        let mut pending = roots;
+        while let Some(id) = pending.pop() {
+            if !live.insert(id) {
+                continue;
+            }
+            let data = self.lookup_id(id)?.ok_or(VaultError::MissingId(id))?;
+            pending.extend(child_ids(&self.type_map, &data)?);
+        }
+        Ok(live)
+    }
+}
@@ -1,108 +1,1175 @@
 use type_vault_trait::*;
-use std::any::TypeId;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Bound, RangeBounds};
+use std::future::Future;
+use std::time::Duration;
+use backend::TransactionTree;
 
-pub struct TypeVault {
-  base_db: sled::Db,
-  id_to_value_map: sled::Tree,
-  value_to_id_map: sled::Tree,
+pub struct TypeVault<B: VaultBackend = SledBackend> {
+  backend: B,
+  id_to_value_map: B::Tree,
+  value_to_id_map: B::Tree,
+  // Keys made of a type tag, the position and encoding of an indexed field, and the id of a value
+  // of that type with that field value. The values are empty.
+  index_map: B::Tree,
+  // Keys made of the id of a value followed by the id of a value that refers to it directly. The
+  // values are empty.
+  referrers_map: B::Tree,
+  // The number of stored values that refer to each value directly, plus the number of roots that
+  // point at it, by its id. Values that nothing refers to have no entry.
+  refcounts_map: B::Tree,
+  // The id each named root points at, by its name.
+  roots_map: B::Tree,
+  // Keys made of the encoded name of a root followed by an increasing sequence number, with the
+  // ids the root was pointed at as values, so that the history of a root is in order.
+  root_history_map: B::Tree,
+  // Keys made of a type tag followed by a sequence number, with the id of a value of that type
+  // that was stored as values, so that the values of a type are in the order they were stored.
+  change_log_map: B::Tree,
+  // The key of the change log entry of each stored value that has one, by its id, so that the
+  // entry can be removed with the value.
+  change_log_keys_map: B::Tree,
+  // Settings the vault was created with, which must stay the same when it is reopened, and the
+  // tags handed out to the types stored in it.
+  meta_map: B::Tree,
+  // Set by `VaultOptions::read_only`.
+  read_only: bool,
+  // Set by `VaultOptions::reference_counts`.
+  reference_counts: bool,
   pub type_map: TypeMap,
 }
 
+pub mod backend;
+mod bulk;
+mod changes;
+mod datalog;
+mod gc;
+mod migration;
+mod roots;
+pub use backend::{memory::MemoryBackend, sled::SledBackend, BackendTransaction, Batch, Entries, Entry, PrefixWatch, VaultBackend, VaultTree};
+#[cfg(feature = "redb")]
+pub use backend::redb::RedbBackend;
+pub use bulk::{BulkReport, BulkWriter};
+pub use changes::{Change, Subscription};
+pub use datalog::{Program, Rule, Term};
+pub use gc::GcReport;
+pub use migration::{MigrationContext, Migrations};
+
+/// Settings that are chosen when a vault is created and that every later `open` must agree with,
+/// the migrations to run when it is opened, and how the store is opened.
+pub struct VaultOptions {
+    hasher: Box<dyn ContentHasher>,
+    id_width: usize,
+    migrations: Migrations,
+    cache_capacity: u64,
+    flush_every: Option<Duration>,
+    compression: bool,
+    temporary: bool,
+    read_only: bool,
+    reference_counts: bool,
+}
+
+impl Default for VaultOptions {
+    fn default() -> Self {
+        VaultOptions {
+            hasher: Box::new(Blake3Hasher),
+            id_width: DEFAULT_ID_WIDTH,
+            migrations: Migrations::new(),
+            cache_capacity: 1024 * 1024 * 1024,
+            flush_every: Some(Duration::from_millis(500)),
+            compression: false,
+            temporary: false,
+            read_only: false,
+            reference_counts: true,
+        }
+    }
+}
+
+impl VaultOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The hash function used to compute value ids. Defaults to `Blake3Hasher`.
+    pub fn hasher(mut self, hasher: impl ContentHasher + 'static) -> Self {
+        self.hasher = Box::new(hasher);
+        self
+    }
+
+    /// How many bytes of the hash make up a value id, between 8 and `MAX_ID_WIDTH`. Wider ids make
+    /// collisions less likely at the cost of space. Defaults to `DEFAULT_ID_WIDTH`.
+    pub fn id_width(mut self, id_width: usize) -> Self {
+        self.id_width = id_width;
+        self
+    }
+
+    /// Conversions for types whose definitions changed since their values were stored. When the
+    /// vault is opened, the stored values of those types are converted, and the values that refer
+    /// to them are rewritten to refer to the converted values, all in one transaction.
+    pub fn migrations(mut self, migrations: Migrations) -> Self {
+        self.migrations = migrations;
+        self
+    }
+
+    /// How many bytes of the vault sled keeps cached in memory. Defaults to 1 GiB. Like the
+    /// settings below, only applies to vaults opened by path, as other backends are opened
+    /// before they are handed to `TypeVault::with_backend`.
+    pub fn cache_capacity(mut self, bytes: u64) -> Self {
+        self.cache_capacity = bytes;
+        self
+    }
+
+    /// How often sled writes what was stored to disk in the background, or `None` to only do so
+    /// when the vault is flushed, see `TypeVault::flush`. Defaults to every 500 milliseconds.
+    pub fn flush_every(mut self, interval: Option<Duration>) -> Self {
+        self.flush_every = interval;
+        self
+    }
+
+    /// Whether sled compresses what it writes to disk, which takes sled's `compression` feature.
+    /// Without it, opening fails with a storage error. Must be the same every time the vault is
+    /// opened. Defaults to no compression.
+    pub fn compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Whether the vault is deleted from disk once it is dropped. sled deletes it as well if
+    /// opening it fails, so this is only meant for vaults that needn't be kept. Defaults to
+    /// keeping it.
+    pub fn temporary(mut self, temporary: bool) -> Self {
+        self.temporary = temporary;
+        self
+    }
+
+    /// Whether everything that would write to the vault fails with `VaultError::ReadOnly`,
+    /// including opening it if its stored types or settings would have to be brought up to date
+    /// first. Defaults to writable.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Whether the number of references to each value is kept up to date as values are put and
+    /// roots are set, which `remove` relies on. Without them writes are cheaper, and values are
+    /// only removed by `TypeVault::gc`. Turning them back on counts every reference when the vault
+    /// is opened. Defaults to keeping them.
+    pub fn reference_counts(mut self, reference_counts: bool) -> Self {
+        self.reference_counts = reference_counts;
+        self
+    }
+}
+
+pub use type_vault_trait::TypeInfo;
+
+const HASHER_KEY: &[u8] = b"hasher";
+const NEXT_TYPE_TAG_KEY: &[u8] = b"next_type_tag";
+// Followed by the type name, maps to the tag and schema of the type.
+const TYPE_KEY_PREFIX: &[u8] = b"type/";
+const ID_WIDTH_KEY: &[u8] = b"id_width";
+// Present once the referrers of every stored value are recorded, which they are not in vaults
+// created before referrers were tracked.
+const REFERRERS_KEY: &[u8] = b"referrers";
+// Present once the reference counts of the stored values are correct. Migrations remove it, as
+// they replace values without counting.
+const REFCOUNTS_KEY: &[u8] = b"refcounts";
+// Present unless some stored values may not be in the change log yet, as a `BulkWriter` logs the
+// values of a batch after it stores them. Those are logged the next time the vault is opened.
+const CHANGE_LOG_KEY: &[u8] = b"change_log";
+// Vaults created before the id width was configurable always used 8 byte ids.
+const LEGACY_ID_WIDTH: usize = 8;
+
 #[macro_export]
 macro_rules! new_type_vault {
     ($e:expr, $($tys:ty),+) => {
-        $crate::TypeVault::new($e, vec![$(std::any::TypeId::of::<$tys>()),+])
+        $crate::TypeVault::new($e, vec![$($crate::TypeInfo::of::<$tys>()),+])
     };
 }
 
 impl TypeVault {
-    pub fn new(path: &std::path::Path, type_ids: Vec<TypeId>) -> Self {
-        let base_db: sled::Db = sled::open(path).expect("Failed to open database");
-        let id_to_value_map = base_db.open_tree("id_to_value").expect("Failed to open id_to_value tree");
-        let value_to_id_map = base_db.open_tree("value_to_id").expect("Failed to open value_to_id tree");
-        let type_map = TypeMap::new(type_ids);
-        TypeVault {
-            base_db,
+    pub fn new(path: &std::path::Path, types: Vec<TypeInfo>) -> Result<Self, VaultError> {
+        Self::with_options(path, types, VaultOptions::default())
+    }
+
+    pub fn with_options(path: &std::path::Path, types: Vec<TypeInfo>, options: VaultOptions) -> Result<Self, VaultError> {
+        let config = sled::Config::new()
+            .path(path)
+            .cache_capacity(options.cache_capacity)
+            .flush_every_ms(options.flush_every.map(|interval| interval.as_millis() as u64))
+            .use_compression(options.compression)
+            .temporary(options.temporary);
+        Self::with_backend(SledBackend::with_config(config)?, types, options)
+    }
+}
+
+impl TypeVault<MemoryBackend> {
+    /// Creates a vault that is kept in memory only and is gone once it is dropped, see
+    /// `MemoryBackend`.
+    pub fn in_memory(types: Vec<TypeInfo>) -> Result<Self, VaultError> {
+        Self::with_backend(MemoryBackend::new(), types, VaultOptions::default())
+    }
+}
+
+impl<B: VaultBackend> TypeVault<B> {
+    /// Opens a vault kept in the trees of `backend`, see `VaultBackend`.
+    pub fn with_backend(backend: B, types: Vec<TypeInfo>, options: VaultOptions) -> Result<Self, VaultError> {
+        let id_to_value_map = backend.open_tree("id_to_value")?;
+        let value_to_id_map = backend.open_tree("value_to_id")?;
+        let index_map = backend.open_tree("index")?;
+        let referrers_map = backend.open_tree("referrers")?;
+        let refcounts_map = backend.open_tree("refcounts")?;
+        let roots_map = backend.open_tree("roots")?;
+        let root_history_map = backend.open_tree("root_history")?;
+        let change_log_map = backend.open_tree("change_log")?;
+        let change_log_keys_map = backend.open_tree("change_log_keys")?;
+        let meta_map = backend.open_tree("meta")?;
+        if !(LEGACY_ID_WIDTH..=MAX_ID_WIDTH).contains(&options.id_width) {
+            return Err(VaultError::Unsupported("id widths outside of 8 to 32 bytes"));
+        }
+        let mut vault = TypeVault {
+            backend,
             id_to_value_map,
             value_to_id_map,
-            type_map,
+            index_map,
+            referrers_map,
+            refcounts_map,
+            roots_map,
+            root_history_map,
+            change_log_map,
+            change_log_keys_map,
+            meta_map,
+            read_only: options.read_only,
+            reference_counts: options.reference_counts,
+            type_map: TypeMap::with_hasher(vec![], options.hasher, options.id_width),
+        };
+        vault.check_hasher()?;
+        vault.check_id_width()?;
+        let stored_types = vault.stored_types()?;
+        let conversions = options.migrations.pending(&stored_types);
+        let (tags, registry_changes) = vault.register_types(types, &stored_types, &conversions)?;
+        let mut reindexed = vec![];
+        for (info, tag) in tags {
+            // The values of converted types are indexed as they are converted.
+            let is_converted = conversions.values().any(|conversion| conversion.from_name == info.name);
+            if let Some((_, stored_schema)) = stored_types.get(info.name) {
+                if stored_schema.indexed_fields() != info.schema.indexed_fields() && !is_converted {
+                    reindexed.push(tag);
+                }
+            }
+            vault.type_map.insert(info, tag);
+        }
+        // Done before the new schemas are stored, so that it is done again if it gets interrupted.
+        for tag in reindexed {
+            vault.reindex(tag)?;
         }
+        if conversions.is_empty() {
+            if !is_applied(&vault.meta_map, &registry_changes)? {
+                vault.check_writable()?;
+                vault.meta_map.apply_batch(registry_changes)?;
+            }
+        } else {
+            migration::migrate(&vault, conversions, registry_changes)?;
+        }
+        if vault.meta_map.get(REFERRERS_KEY)?.is_none() {
+            vault.record_referrers()?;
+        }
+        let has_refcounts = vault.meta_map.get(REFCOUNTS_KEY)?.is_some();
+        if vault.reference_counts && !has_refcounts {
+            vault.count_references()?;
+        } else if !vault.reference_counts && has_refcounts && !vault.read_only {
+            // The counts go out of date with the next write.
+            vault.meta_map.remove(REFCOUNTS_KEY)?;
+        }
+        if vault.meta_map.get(CHANGE_LOG_KEY)?.is_none() && !vault.read_only {
+            vault.complete_change_log()?;
+        }
+        Ok(vault)
     }
 
-    pub fn clear(&self) -> Result<(), sled::Error> {
+    // Logs the stored values that have a change log key but no entry under it, see
+    // `CHANGE_LOG_KEY`, and forgets the keys of values that were never stored.
+    fn complete_change_log(&self) -> Result<(), VaultError> {
+        let mut missing = Batch::default();
+        let mut unstored = Batch::default();
+        for entry in self.change_log_keys_map.iter() {
+            let (id, key) = entry?;
+            if !self.id_to_value_map.contains_key(&id)? {
+                unstored.remove(id);
+            } else if !self.change_log_map.contains_key(&key)? {
+                missing.insert(key, id);
+            }
+        }
+        self.change_log_map.apply_batch(missing)?;
+        self.change_log_keys_map.apply_batch(unstored)?;
+        self.meta_map.insert(CHANGE_LOG_KEY, [])?;
+        Ok(())
+    }
+
+    // The tag and schema of every type stored in the vault, by type name.
+    fn stored_types(&self) -> Result<HashMap<String, (u64, Schema)>, VaultError> {
+        let mut stored_types = HashMap::new();
+        for entry in self.meta_map.scan_prefix(TYPE_KEY_PREFIX) {
+            let (key, bytes) = entry?;
+            let name = String::from_utf8_lossy(&key[TYPE_KEY_PREFIX.len()..]).into_owned();
+            let (tag_and_schema, _) = bincode::serde::decode_from_slice(&bytes, BINCODE_CONFIG)?;
+            stored_types.insert(name, tag_and_schema);
+        }
+        Ok(stored_types)
+    }
+
+    // Looks up the tags of the given types by their names, handing out new tags to types that
+    // were never stored before. Tags are never reused, so reordering or adding types is harmless.
+    // Fails if any type is incompatible with its stored schema, unless its values get converted.
+    // Returns the changes to the stored types instead of applying them, so that a migration can
+    // apply them together with the converted values.
+    fn register_types(&self, types: Vec<TypeInfo>, stored_types: &HashMap<String, (u64, Schema)>, conversions: &HashMap<u64, &migration::Conversion>)
+        -> Result<(Vec<(TypeInfo, u64)>, Batch), VaultError> {
+        let mut names = HashSet::new();
+        if let Some(duplicate) = types.iter().find(|info| !names.insert(info.name)) {
+            return Err(VaultError::DuplicateTypeName(duplicate.name));
+        }
+        let mut next_tag = match self.meta_map.get(NEXT_TYPE_TAG_KEY)? {
+            Some(bytes) => bincode::serde::decode_from_slice(&bytes, BINCODE_CONFIG)?.0,
+            None => FIRST_TYPE_TAG,
+        };
+        let mut changed_types = Batch::default();
+        let mut tags = Vec::new();
+        let mut mismatches = Vec::new();
+        for info in types {
+            let key = [TYPE_KEY_PREFIX, info.name.as_bytes()].concat();
+            let tag = match stored_types.get(info.name) {
+                Some((tag, schema)) => {
+                    let differences = info.schema.incompatibilities(schema);
+                    let is_converted = conversions.values().any(|conversion| conversion.from_name == info.name);
+                    if !differences.is_empty() && !is_converted {
+                        mismatches.push(SchemaMismatch { type_name: info.name, differences });
+                    } else if *schema != info.schema {
+                        // Only names changed, or the values get converted to the new schema.
+                        changed_types.insert(key, bincode::serde::encode_to_vec((tag, &info.schema), BINCODE_CONFIG)?);
+                    }
+                    *tag
+                },
+                None => {
+                    let tag = next_tag;
+                    next_tag += 1;
+                    changed_types.insert(key, bincode::serde::encode_to_vec((tag, &info.schema), BINCODE_CONFIG)?);
+                    tag
+                },
+            };
+            tags.push((info, tag));
+        }
+        if !mismatches.is_empty() {
+            return Err(VaultError::SchemaMismatch(mismatches));
+        }
+        // Values of types that are converted to another type name are all gone after migrating.
+        for conversion in conversions.values() {
+            if conversion.renames() && !names.contains(conversion.from_name) {
+                changed_types.remove([TYPE_KEY_PREFIX, conversion.from_name.as_bytes()].concat());
+            }
+        }
+        changed_types.insert(NEXT_TYPE_TAG_KEY, bincode::serde::encode_to_vec(next_tag, BINCODE_CONFIG)?);
+        Ok((tags, changed_types))
+    }
+
+    // Rebuilds the index entries of the values of a type, after the fields it indexes changed.
+    fn reindex(&self, tag: u64) -> Result<(), VaultError> {
+        self.check_writable()?;
+        let tag = bincode::serde::encode_to_vec(tag, BINCODE_CONFIG)?;
+        let mut changes = Batch::default();
+        for entry in self.index_map.scan_prefix(&tag) {
+            changes.remove(entry?.0);
+        }
+        for entry in self.value_to_id_map.scan_prefix(&tag) {
+            let (data, id_bytes) = entry?;
+            for key in index_keys(&self.type_map, &data, id_from_bytes(&id_bytes)?)? {
+                changes.insert(key, []);
+            }
+        }
+        self.index_map.apply_batch(changes)
+    }
+
+    // Records the referrers of every stored value. Values of types that are not registered can't
+    // be decoded, so if there are any, it is tried again the next time the vault is opened.
+    fn record_referrers(&self) -> Result<(), VaultError> {
+        self.check_writable()?;
+        let mut changes = Batch::default();
+        let mut is_complete = true;
+        for entry in self.id_to_value_map.iter() {
+            let (id_bytes, data) = entry?;
+            match referrer_keys(&self.type_map, &data, id_from_bytes(&id_bytes)?) {
+                Ok(keys) => keys.into_iter().for_each(|key| changes.insert(key, [])),
+                Err(VaultError::UnknownTypeTag(_)) => is_complete = false,
+                Err(err) => return Err(err),
+            }
+        }
+        self.referrers_map.apply_batch(changes)?;
+        if is_complete {
+            self.meta_map.insert(REFERRERS_KEY, [])?;
+        }
+        Ok(())
+    }
+
+    // Counts the references to every stored value from scratch. Like `record_referrers`, it is
+    // tried again the next time the vault is opened if there are values of unregistered types.
+    fn count_references(&self) -> Result<(), VaultError> {
+        self.check_writable()?;
+        let mut refcounts: HashMap<ValueId, u64> = HashMap::new();
+        for entry in self.id_to_value_map.iter() {
+            match child_ids(&self.type_map, &entry?.1) {
+                Ok(children) => children.into_iter().for_each(|child| *refcounts.entry(child).or_default() += 1),
+                Err(VaultError::UnknownTypeTag(_)) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+        for root in self.roots() {
+            let (_, id) = root?;
+            *refcounts.entry(id).or_default() += 1;
+        }
+        self.refcounts_map.clear()?;
+        let mut changes = Batch::default();
+        for (id, refcount) in refcounts {
+            changes.insert(id.as_bytes(), bincode::serde::encode_to_vec(refcount, BINCODE_CONFIG)?);
+        }
+        self.refcounts_map.apply_batch(changes)?;
+        self.meta_map.insert(REFCOUNTS_KEY, [])?;
+        Ok(())
+    }
+
+    // Records the hasher of a new vault, or checks that an existing vault was created with the
+    // configured one. Opening it with another hasher would silently give every value a new id.
+    fn check_hasher(&self) -> Result<(), VaultError> {
+        let configured = self.type_map.hasher().name();
+        match self.meta_map.get(HASHER_KEY)? {
+            Some(stored) if stored == configured.as_bytes() => Ok(()),
+            Some(stored) => Err(VaultError::HasherMismatch {
+                stored: String::from_utf8_lossy(&stored).into_owned(),
+                configured,
+            }),
+            // Vaults written before the hasher was recorded used the standard library's
+            // `DefaultHasher`, which no longer exists as an option.
+            None if !self.id_to_value_map.is_empty()? => Err(VaultError::HasherMismatch {
+                stored: "unknown".to_owned(),
+                configured,
+            }),
+            None => {
+                self.check_writable()?;
+                self.meta_map.insert(HASHER_KEY, configured.as_bytes())?;
+                Ok(())
+            },
+        }
+    }
+
+    fn check_id_width(&self) -> Result<(), VaultError> {
+        let configured = self.type_map.id_width();
+        let stored = match self.meta_map.get(ID_WIDTH_KEY)? {
+            Some(stored) => stored.first().copied().map(usize::from)
+                .ok_or_else(|| VaultError::Corrupt("empty id width".to_owned()))?,
+            None if !self.id_to_value_map.is_empty()? => LEGACY_ID_WIDTH,
+            None => {
+                self.check_writable()?;
+                self.meta_map.insert(ID_WIDTH_KEY, [configured as u8])?;
+                configured
+            },
+        };
+        if stored != configured {
+            return Err(VaultError::IdWidthMismatch { stored, configured });
+        }
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<(), VaultError> {
+        self.check_writable()?;
         self.id_to_value_map.clear()?;
         self.value_to_id_map.clear()?;
+        self.index_map.clear()?;
+        self.referrers_map.clear()?;
+        self.refcounts_map.clear()?;
+        self.roots_map.clear()?;
+        self.root_history_map.clear()?;
+        self.change_log_map.clear()?;
+        self.change_log_keys_map.clear()?;
         Ok(())
     }
 
-    pub fn put<T:VaultType>(&self, value: &T) -> Result<(), sled::Error> {
-        let data = serialize_type(value, &self.type_map);
-        for (val, id) in data {
-            self.value_to_id_map.insert(&val, &id)?;
-            self.id_to_value_map.insert(id, val)?;
+    /// Stores `value` and all of its nested values, returning the id of `value` itself. Nested
+    /// values that are stored already are skipped with everything nested in them, but `value` is
+    /// serialized and hashed in full to find out which they are.
+    pub fn put<T:VaultType>(&self, value: &T) -> Result<ValueId, VaultError> {
+        self.transaction(|tx| tx.put(value))
+    }
+
+    /// Removes the value stored under `id`, together with every value nested in it that no other
+    /// stored value refers to, see `VaultTransaction::remove`.
+    pub fn remove(&self, id: ValueId) -> Result<bool, VaultError> {
+        self.check_refcounts()?;
+        self.transaction(|tx| tx.remove(id))
+    }
+
+    // Refuses writes to vaults opened with `VaultOptions::read_only`.
+    fn check_writable(&self) -> Result<(), VaultError> {
+        if self.read_only {
+            return Err(VaultError::ReadOnly);
         }
         Ok(())
     }
 
-    pub fn scan<'a, T: VaultType>(&'a self, value: T, fields_in_prefix: u64) -> impl Iterator<Item = (Box<T>, ValueId)> + 'a {
-        let prefix = value.serialize_prefix(fields_in_prefix, &self.type_map);
-        self.debug_scan(prefix)
+    /// Waits until everything stored so far is written to disk, so that it survives a crash.
+    pub fn flush(&self) -> Result<(), VaultError> {
+        self.backend.flush()
     }
 
-    // Shouldn't be public
-    pub fn debug_scan<'a, T:VaultType>(&'a self, prefix : Vec<u8>) -> impl Iterator<Item = (Box<T>, ValueId)>  + 'a {
-        self.value_to_id_map
-            .scan_prefix(prefix)
-            //TODO: We want to report an error instead of silently ignoring deserialization failures.
-            .filter_map(move |res: Result<(sled::IVec, sled::IVec), sled::Error>| {
-                let (data, id_bytes) = res.expect("Failed to read from value_to_id_map");
-                let id = bincode::serde::decode_from_slice(&id_bytes, BINCODE_CONFIG).unwrap().0;
-                let deserialized = deserialize_type::<T>(&data, &|id_needle| self.lookup_id(id_needle));
-                let deserialized = match deserialized {
-                    None => {
-                        eprintln!("Failed to deserialize data with ID {:?}", id);
-                        return None;
-                    },
-                    Some(d) => d,
+    /// Like `flush`, but doesn't block. The returned future completes once everything stored so
+    /// far is written to disk.
+    pub fn flush_async(&self) -> impl Future<Output = Result<(), VaultError>> + '_ {
+        self.backend.flush_async()
+    }
+
+    // Removing values relies on the reference counts, which are not known while there are values
+    // of types that are not registered.
+    fn check_refcounts(&self) -> Result<(), VaultError> {
+        if !self.reference_counts {
+            return Err(VaultError::Unsupported("removing values without reference counts"));
+        }
+        match self.meta_map.get(REFCOUNTS_KEY)? {
+            Some(_) => Ok(()),
+            None => Err(VaultError::Unsupported("removing values while some stored types are not registered")),
+        }
+    }
+
+    /// Reads back the value stored under `id`, or `None` if there is no such value.
+    pub fn get<T:VaultType>(&self, id: ValueId) -> Result<Option<T>, VaultError> {
+        match self.lookup_id(id)? {
+            None => Ok(None),
+            Some(data) => deserialize_type::<T>(&data, &self.type_map, &|id_needle| self.lookup_id(id_needle)).map(Some),
+        }
+    }
+
+    /// Runs `f` as a single atomic transaction over the vault: either every value put through
+    /// the `VaultTransaction` is stored, including all of its nested values, or none of them are.
+    /// `f` may be called more than once if the transaction conflicts with a concurrent writer.
+    pub fn transaction<R>(&self, f: impl Fn(&VaultTransaction) -> Result<R, VaultError>) -> Result<R, VaultError> {
+        self.check_writable()?;
+        let trees = [&self.id_to_value_map, &self.value_to_id_map, &self.index_map, &self.referrers_map, &self.refcounts_map, &self.change_log_map, &self.change_log_keys_map];
+        self.backend.transaction(&trees, &|btx| {
+            let tree = |tree| TransactionTree { tx: btx, tree };
+            let tx = VaultTransaction {
+                id_to_value_map: tree(0),
+                value_to_id_map: tree(1),
+                index_map: tree(2),
+                referrers_map: tree(3),
+                refcounts_map: tree(4),
+                change_log_map: tree(5),
+                change_log_keys_map: tree(6),
+                type_map: &self.type_map,
+                reference_counts: self.reference_counts,
+            };
+            f(&tx)
+        })
+    }
+
+    pub fn scan<'a, T: VaultType>(&'a self, value: T, fields_in_prefix: u64) -> Result<impl Iterator<Item = Result<(Box<T>, ValueId), VaultError>> + 'a, VaultError> {
+        let prefix = value.serialize_prefix(fields_in_prefix, &self.type_map)?;
+        Ok(self.debug_scan(prefix))
+    }
+
+    /// Every stored value that matches `query`, in no particular order. Nested queries on fields
+    /// stored by id are run first. Only the values found through the `access_path` of the query
+    /// are read, and the constraints it doesn't cover are checked on each of them before it is
+    /// decoded.
+    #[allow(clippy::type_complexity)]
+    pub fn query<'a, Q: VaultQuery>(&'a self, query: &Q) -> Result<impl Iterator<Item = Result<(Box<Q::Target>, ValueId), VaultError>> + 'a, VaultError>
+    where Q::Target: 'static {
+        let info = self.type_map.info_of::<Q::Target>()?;
+        let constraints = self.resolve(query.field_constraints(&self.type_map)?)?;
+        Ok(self.find(info, constraints)?.map(move |candidate| {
+            let (data, id) = candidate?;
+            let deserialized = deserialize_type::<Q::Target>(&data, &self.type_map, &|id_needle| self.lookup_id(id_needle))?;
+            Ok((Box::new(deserialized), id))
+        }))
+    }
+
+    /// How `query` finds the values that match a query: by the longest run of constrained
+    /// leading fields, which the values are ordered by, or if there is none, by the index of a
+    /// constrained field, or else through the values that match a nested query. Without any of
+    /// those it scans every value of the type.
+    pub fn access_path<Q: VaultQuery>(&self, query: &Q) -> Result<AccessPath, VaultError> where Q::Target: 'static {
+        let info = self.type_map.info_of::<Q::Target>()?;
+        Ok(plan(info, &self.resolve(query.field_constraints(&self.type_map)?)?))
+    }
+
+    // Runs the nested queries among `constraints`, replacing them with the ids they found.
+    fn resolve(&self, constraints: Vec<FieldConstraint>) -> Result<Vec<Resolved>, VaultError> {
+        constraints.into_iter().map(|constraint| Ok(match constraint {
+            FieldConstraint::Any => Resolved::Any,
+            FieldConstraint::Equals(value) => Resolved::Equals(value),
+            FieldConstraint::Matching(query) => {
+                let info = query.target(&self.type_map)?;
+                let mut ids = HashMap::new();
+                for candidate in self.find(info, self.resolve(query.constraints(&self.type_map)?)?)? {
+                    let (_, id) = candidate?;
+                    ids.insert(bincode::serde::encode_to_vec(id, BINCODE_CONFIG)?, id);
+                }
+                Resolved::OneOf(ids)
+            },
+        })).collect()
+    }
+
+    // The stored values of the type described by `info` that satisfy all of `constraints`.
+    #[allow(clippy::type_complexity)]
+    fn find<'a>(&'a self, info: &TypeInfo, constraints: Vec<Resolved>) -> Result<Box<dyn Iterator<Item = Result<(Vec<u8>, ValueId), VaultError>> + 'a>, VaultError> {
+        let path = plan(info, &constraints);
+        let tag = self.type_map.get(&info.type_id).ok_or(VaultError::UnregisteredType(info.name))?;
+        let candidates: Box<dyn Iterator<Item = Result<(Vec<u8>, ValueId), VaultError>>> = match path {
+            AccessPath::Prefix { fields } => {
+                let mut prefix = tag.clone();
+                for constraint in &constraints[..fields] {
+                    if let Resolved::Equals(value) = constraint {
+                        prefix.extend(value);
+                    }
+                }
+                Box::new(self.scan_raw(prefix))
+            },
+            AccessPath::Index { field } => match &constraints[field] {
+                Resolved::Equals(value) => Box::new(self.index_lookup(index_prefix(&tag, field as u64, value)?)),
+                _ => unreachable!("only fields with a value are looked up by index"),
+            },
+            AccessPath::Nested { field } => {
+                let Resolved::OneOf(ids) = &constraints[field] else { unreachable!("only nested queries are followed") };
+                let is_indexed = info.schema.indexed_fields().contains(&(field as u64));
+                let mut lookups: Vec<Box<dyn Iterator<Item = Result<(Vec<u8>, ValueId), VaultError>>>> = vec![];
+                for (value, id) in ids {
+                    lookups.push(if field == 0 {
+                        Box::new(self.scan_raw([tag.as_slice(), value].concat()))
+                    } else if is_indexed {
+                        Box::new(self.index_lookup(index_prefix(&tag, field as u64, value)?))
+                    } else {
+                        // The referrers of a value may be of any type, and refer to it from any field.
+                        let tag = tag.clone();
+                        Box::new(self.referrers(*id).filter_map(move |parent| {
+                            let lookup = || {
+                                let parent = parent?;
+                                let data = self.id_to_value_map.get(parent)?;
+                                match data {
+                                    Some(data) if data.starts_with(&tag) && own_type(&self.type_map, &data)?.is_some() => Ok(Some((data, parent))),
+                                    _ => Ok(None),
+                                }
+                            };
+                            lookup().transpose()
+                        }))
+                    });
+                }
+                Box::new(lookups.into_iter().flatten())
+            },
+        };
+        let remaining: Vec<(usize, Resolved)> = constraints.into_iter().enumerate()
+            .filter(|(field, constraint)| !path.covers(*field) && !matches!(constraint, Resolved::Any))
+            .collect();
+        let encoded_fields = info.encoded_fields;
+        Ok(Box::new(candidates
+            .map(move |candidate| {
+                let (data, id) = candidate?;
+                if !remaining.is_empty() {
+                    let fields = encoded_fields(&data[tag.len()..])?;
+                    if !remaining.iter().all(|(field, constraint)| fields.get(*field).is_some_and(|value| constraint.allows(value))) {
+                        return Ok(None);
+                    }
+                }
+                Ok(Some((data, id)))
+            })
+            .filter_map(Result::transpose)))
+    }
+
+    // The stored values with index entries that start with `prefix`, which is followed by their id.
+    fn index_lookup(&self, prefix: Vec<u8>) -> impl Iterator<Item = Result<(Vec<u8>, ValueId), VaultError>> + '_ {
+        self.index_map.scan_prefix(&prefix).filter_map(move |entry| {
+            let lookup = || {
+                let id = id_from_bytes(&entry?.0[prefix.len()..])?;
+                // Index entries may outlive their values, see `BulkWriter`.
+                let data = self.id_to_value_map.get(id)?;
+                Ok(data.map(|data| (data, id)))
+            };
+            lookup().transpose()
+        })
+    }
+
+    /// The stored values of type `T` whose inline field `field`, of type `V`, lies in `range`, in
+    /// the order of that field. Values are ordered by their first field and index entries by the
+    /// indexed field, so for those only the values in the range are read. For any other field
+    /// every value of the type is read and sorted.
+    #[allow(clippy::type_complexity)]
+    pub fn scan_range<'a, T, V>(&'a self, field: &str, range: impl RangeBounds<V>) -> Result<Box<dyn Iterator<Item = Result<(Box<T>, ValueId), VaultError>> + 'a>, VaultError>
+    where T: VaultType + 'static, V: OrderedKey {
+        let unknown_field = || VaultError::UnknownField { type_name: T::TYPE_NAME, field: field.to_owned(), ty: std::any::type_name::<V>() };
+        let Schema::Struct(fields) = &self.type_map.info_of::<T>()?.schema else { return Err(unknown_field()) };
+        let ty = std::any::type_name::<V>().split_whitespace().collect::<String>();
+        let position = fields.iter()
+            .position(|schema| schema.name == field && schema.storage == FieldStorage::Inline && schema.ty == ty)
+            .ok_or_else(unknown_field)?;
+        let bounds = (encode_bound(range.start_bound())?, encode_bound(range.end_bound())?);
+        let tag = self.type_map.tag_of::<T>()?;
+        let decode = move |candidate: Result<(Vec<u8>, ValueId), VaultError>| {
+            let (data, id) = candidate?;
+            let deserialized = deserialize_type::<T>(&data, &self.type_map, &|id_needle| self.lookup_id(id_needle))?;
+            Ok((Box::new(deserialized), id))
+        };
+        if position == 0 {
+            let values = self.value_to_id_map.range(key_range(&tag, &bounds)).filter_map(|entry| {
+                let own_value = || {
+                    let (data, id_bytes) = entry?;
+                    if own_type(&self.type_map, &data)?.is_none() {
+                        return Ok(None);
+                    }
+                    Ok(Some((data, id_from_bytes(&id_bytes)?)))
+                };
+                own_value().transpose()
+            });
+            Ok(Box::new(values.map(decode)))
+        } else if fields[position].indexed {
+            let prefix = index_prefix(&tag, position as u64, &[])?;
+            let id_width = self.type_map.id_width();
+            let values = self.index_map.range(key_range(&prefix, &bounds)).filter_map(move |entry| {
+                let lookup = || {
+                    let (key, _) = entry?;
+                    let id = id_from_bytes(&key[key.len().saturating_sub(id_width)..])?;
+                    // Index entries may outlive their values, see `BulkWriter`.
+                    let data = self.id_to_value_map.get(id)?;
+                    Ok(data.map(|data| (data, id)))
                 };
-                Some ((Box::new(deserialized), id))
+                lookup().transpose()
+            });
+            Ok(Box::new(values.map(decode)))
+        } else {
+            let mut values = vec![];
+            for entry in self.scan_raw(tag) {
+                let (data, id) = entry?;
+                let mut fields = T::encoded_fields(self.type_map.strip_tag::<T>(&data)?)?;
+                let value = std::mem::take(&mut fields[position]);
+                if (bounds.0.as_ref(), bounds.1.as_ref()).contains(&value) {
+                    values.push((value, data, id));
+                }
+            }
+            values.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+            Ok(Box::new(values.into_iter().map(|(_, data, id)| Ok((data, id))).map(decode)))
+        }
+    }
+
+    /// Every stored value of type `T`, in no particular order.
+    pub fn scan_all<'a, T: VaultType + 'static>(&'a self) -> Result<impl Iterator<Item = Result<(Box<T>, ValueId), VaultError>> + 'a, VaultError> {
+        Ok(self.debug_scan(self.type_map.tag_of::<T>()?))
+    }
+
+    /// The number of stored values of type `T`. Unlike `scan_all`, the values they refer to are
+    /// not looked up.
+    pub fn count<T: VaultType + 'static>(&self) -> Result<usize, VaultError> {
+        let mut count = 0;
+        for entry in self.scan_raw(self.type_map.tag_of::<T>()?) {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Whether any value of type `T` is stored, without looking up the values it refers to.
+    pub fn exists<T: VaultType + 'static>(&self) -> Result<bool, VaultError> {
+        match self.scan_raw(self.type_map.tag_of::<T>()?).next() {
+            Some(entry) => entry.map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// The ids of the stored values that refer to the value stored under `id` directly, in no
+    /// particular order.
+    pub fn referrers(&self, id: ValueId) -> impl Iterator<Item = Result<ValueId, VaultError>> + '_ {
+        self.referrers_map.scan_prefix(id).filter_map(move |entry| {
+            let lookup = || {
+                let parent = id_from_bytes(&entry?.0[id.width()..])?;
+                // Entries may outlive their values, see `BulkWriter`.
+                Ok(self.id_to_value_map.contains_key(parent)?.then_some(parent))
+            };
+            lookup().transpose()
+        })
+    }
+
+    /// The stored values of type `T` that refer to the value stored under `id` directly, in no
+    /// particular order.
+    #[allow(clippy::type_complexity)]
+    pub fn referrers_of_type<'a, T: VaultType + 'static>(&'a self, id: ValueId) -> Result<impl Iterator<Item = Result<(Box<T>, ValueId), VaultError>> + 'a, VaultError> {
+        let tag = self.type_map.tag_of::<T>()?;
+        Ok(self.referrers(id).filter_map(move |parent| {
+            let lookup = || {
+                let parent = parent?;
+                match self.lookup_id(parent)? {
+                    Some(data) if data.starts_with(&tag) => {
+                        let deserialized = deserialize_type::<T>(&data, &self.type_map, &|id_needle| self.lookup_id(id_needle))?;
+                        Ok(Some((Box::new(deserialized), parent)))
+                    },
+                    _ => Ok(None),
+                }
+            };
+            lookup().transpose()
+        }))
+    }
+
+    // Shouldn't be public
+    pub fn debug_scan<'a, T:VaultType>(&'a self, prefix : Vec<u8>) -> impl Iterator<Item = Result<(Box<T>, ValueId), VaultError>>  + 'a {
+        self.scan_raw(prefix)
+            .map(move |entry| {
+                let (data, id) = entry?;
+                let deserialized = deserialize_type::<T>(&data, &self.type_map, &|id_needle| self.lookup_id(id_needle))?;
+                Ok((Box::new(deserialized), id))
             })
     }
 
-    pub fn debug_scan_primitive(&self, prefix: Vec<u8>) -> impl Iterator<Item = (Vec<u8>, ValueId)> {
+    pub fn debug_scan_primitive(&self, prefix: Vec<u8>) -> impl Iterator<Item = Result<(Vec<u8>, ValueId), VaultError>> + '_ {
+        self.scan_raw(prefix)
+    }
+
+    // The stored values that start with `prefix` and are stored under a tag of their own, see
+    // `own_type`.
+    fn scan_raw(&self, prefix: Vec<u8>) -> impl Iterator<Item = Result<(Vec<u8>, ValueId), VaultError>> + '_ {
         self.value_to_id_map
             .scan_prefix(prefix)
-            .map(|res: Result<(sled::IVec, sled::IVec), sled::Error>| {
-                let (value_data, id_bytes) = res.expect("Failed to read from value_to_id_map");
-                let mut id_array = [0u8; 8];
-                id_array.copy_from_slice(&id_bytes);
-                (value_data.to_vec(), id_array)
+            .filter_map(|entry| {
+                let own_value = || {
+                    let (value_data, id_bytes) = entry?;
+                    if own_type(&self.type_map, &value_data)?.is_none() {
+                        return Ok(None);
+                    }
+                    Ok(Some((value_data, id_from_bytes(&id_bytes)?)))
+                };
+                own_value().transpose()
             })
     }
 
-    pub fn debug_print(&self) {
+    pub fn debug_print(&self) -> Result<(), VaultError> {
         println!("TypeVault contents:\nValue to ID map:");
         for item in self.value_to_id_map.iter() {
-            let (key, value) = item.expect("Failed to read from value_to_id_map");
-            let mut value_array = [0u8; 8];
-            value_array.copy_from_slice(&value);
-            println!("Value : {:?}, ID: {:?}", key, value_array);
+            let (key, value) = item?;
+            println!("Value : {:?}, ID: {:?}", key, id_from_bytes(&value)?);
         }
         println!("ID to Value map:");
         for item in self.id_to_value_map.iter() {
-            let (key, value) = item.expect("Failed to read from id_to_value_map");
-            let mut key_array = [0u8; 8];
-            key_array.copy_from_slice(&key);
-            let id = u64::from_be_bytes(key_array);
-            println!("ID: {}, Data: {:?}", id, value);
+            let (key, value) = item?;
+            println!("ID: {}, Data: {:?}", id_from_bytes(&key)?, value);
         }
+        Ok(())
     }
 
-    fn lookup_id(&self, id: ValueId) -> Option<Vec<u8>> {
-        if let Ok(Some(id_bytes)) = self.id_to_value_map.get(id) {
-            Some(id_bytes.to_vec())
-        } else {
-            None
+    fn lookup_id(&self, id: ValueId) -> Result<Option<Vec<u8>>, VaultError> {
+        let data = self.id_to_value_map.get(id)?;
+        Ok(data.map(|data| data.to_vec()))
+    }
+}
+
+/// How `TypeVault::query` finds the values that match a query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessPath {
+    /// Scans the values whose first `fields` fields have the constrained values. With no fields,
+    /// that is every value of the type.
+    Prefix { fields: usize },
+    /// Looks up the values by the index of the given field.
+    Index { field: usize },
+    /// Runs the nested query on the given field first, and looks up the values that refer to what
+    /// it found: by prefix if it is the first field, by its index if it has one, and otherwise by
+    /// the referrers of each value found.
+    Nested { field: usize },
+}
+
+impl AccessPath {
+    // Whether the values found this way are known to satisfy the constraint on `field`.
+    fn covers(&self, field: usize) -> bool {
+        match self {
+            AccessPath::Prefix { fields } => field < *fields,
+            AccessPath::Index { field: indexed } => field == *indexed,
+            // Referrers may refer to the values found from another field.
+            AccessPath::Nested { .. } => false,
+        }
+    }
+}
+
+// A field constraint, with nested queries replaced by the ids of the values they found, keyed by
+// their encoding.
+enum Resolved {
+    Any,
+    Equals(Vec<u8>),
+    OneOf(HashMap<Vec<u8>, ValueId>),
+}
+
+impl Resolved {
+    fn allows(&self, value: &[u8]) -> bool {
+        match self {
+            Resolved::Any => true,
+            Resolved::Equals(expected) => expected == value,
+            Resolved::OneOf(ids) => ids.contains_key(value),
+        }
+    }
+}
+
+fn plan(info: &TypeInfo, constraints: &[Resolved]) -> AccessPath {
+    let leading = constraints.iter().take_while(|constraint| matches!(constraint, Resolved::Equals(_))).count();
+    if leading == 0 {
+        let indexed = info.schema.indexed_fields();
+        if let Some(field) = indexed.into_iter().map(|field| field as usize).find(|field| matches!(constraints[*field], Resolved::Equals(_))) {
+            return AccessPath::Index { field };
+        }
+        if let Some(field) = constraints.iter().position(|constraint| matches!(constraint, Resolved::OneOf(_))) {
+            return AccessPath::Nested { field };
+        }
+    }
+    AccessPath::Prefix { fields: leading }
+}
+
+pub struct VaultTransaction<'a> {
+    id_to_value_map: TransactionTree<'a>,
+    value_to_id_map: TransactionTree<'a>,
+    index_map: TransactionTree<'a>,
+    referrers_map: TransactionTree<'a>,
+    refcounts_map: TransactionTree<'a>,
+    change_log_map: TransactionTree<'a>,
+    change_log_keys_map: TransactionTree<'a>,
+    type_map: &'a TypeMap,
+    // Whether to keep `refcounts_map` up to date, see `VaultOptions::reference_counts`.
+    reference_counts: bool,
+}
+
+impl VaultTransaction<'_> {
+    pub fn put<T:VaultType>(&self, value: &T) -> Result<ValueId, VaultError> {
+        // The `Shared` parts of the value that are stored already are not serialized again.
+        let data = serialize_type_skipping(value, self.type_map, &|id| Ok(self.id_to_value_map.get(id)?.is_some()))?;
+        // The value itself comes last, after all of its nested values.
+        let root_id = data.last().map(|(_val, id)| *id)
+            .expect("serialize_type always returns the value itself");
+        // Putting a small change to a big value only looks up and writes the values on the path
+        // to the change, see `unstored`.
+        for (val, id) in unstored(self.type_map, data, |id| self.id_to_value_map.get(id))? {
+            self.store(val, id)?;
+        }
+        Ok(root_id)
+    }
+
+    // Stores serialized data under its id, together with its index, referrers and change log
+    // entries, and counts it as a reference to the values it refers to.
+    pub(crate) fn store(&self, val: Vec<u8>, id: ValueId) -> Result<(), VaultError> {
+        match self.id_to_value_map.get(id)? {
+            // Already stored, by an earlier put or as a value shared within this one.
+            Some(stored) if stored == val.as_slice() => return Ok(()),
+            Some(_) => return Err(VaultError::IdCollision(id)),
+            None => {},
+        }
+        for key in index_keys(self.type_map, &val, id)? {
+            self.index_map.insert(key, [])?;
+        }
+        for child in child_ids(self.type_map, &val)? {
+            self.referrers_map.insert([child.as_bytes(), id.as_bytes()].concat(), [])?;
+            if self.reference_counts {
+                set_refcount(&self.refcounts_map, child, refcount(&self.refcounts_map, child)? + 1)?;
+            }
+        }
+        // Values that are not stored under a tag of their own are not logged.
+        if own_type(self.type_map, &val)?.is_some() {
+            let (_, tag_len) = decode_tag(&val)?;
+            let sequence = self.change_log_map.tx.generate_id()?;
+            let key = [&val[..tag_len], &sequence.to_be_bytes()].concat();
+            self.change_log_map.insert(&key, id.as_bytes())?;
+            self.change_log_keys_map.insert(id.as_bytes(), &key)?;
+        }
+        self.value_to_id_map.insert(val.as_slice(), id.as_bytes())?;
+        self.id_to_value_map.insert(id.as_bytes(), val)?;
+        Ok(())
+    }
+
+    /// Removes the value stored under `id`, and every value nested in it that no other stored
+    /// value refers to, all the way down. Fails with `VaultError::StillReferenced` if another
+    /// stored value refers to the value itself, or a root points at it. Nested values that a root
+    /// points at are kept. A value that was also put on its own is removed with the last value it
+    /// is nested in. Returns whether there was a value stored under `id`. Needs reference counts,
+    /// see `VaultOptions::reference_counts`.
+    pub fn remove(&self, id: ValueId) -> Result<bool, VaultError> {
+        if !self.reference_counts {
+            return Err(VaultError::Unsupported("removing values without reference counts"));
+        }
+        if self.id_to_value_map.get(id)?.is_none() {
+            return Ok(false);
+        }
+        if refcount(&self.refcounts_map, id)? > 0 {
+            return Err(VaultError::StillReferenced(id));
+        }
+        let mut unreferenced = vec![id];
+        while let Some(id) = unreferenced.pop() {
+            let Some(data) = self.id_to_value_map.remove(id.as_bytes())? else { continue };
+            self.value_to_id_map.remove(&data)?;
+            if let Some(key) = self.change_log_keys_map.remove(id.as_bytes())? {
+                self.change_log_map.remove(key)?;
+            }
+            for key in index_keys(self.type_map, &data, id)? {
+                self.index_map.remove(key)?;
+            }
+            for child in child_ids(self.type_map, &data)? {
+                self.referrers_map.remove([child.as_bytes(), id.as_bytes()].concat())?;
+                let refcount = refcount(&self.refcounts_map, child)?.checked_sub(1)
+                    .ok_or_else(|| VaultError::Corrupt(format!("value {} has no references left to remove", child)))?;
+                set_refcount(&self.refcounts_map, child, refcount)?;
+                if refcount == 0 {
+                    unreferenced.push(child);
+                }
+            }
         }
+        Ok(true)
     }
-}
\ No newline at end of file
+
+}
+
+// The number of references to `id`, see `TypeVault::refcounts_map`.
+fn refcount(refcounts_map: &TransactionTree, id: ValueId) -> Result<u64, VaultError> {
+    match refcounts_map.get(id)? {
+        Some(bytes) => Ok(bincode::serde::decode_from_slice(&bytes, BINCODE_CONFIG)?.0),
+        None => Ok(0),
+    }
+}
+
+fn set_refcount(refcounts_map: &TransactionTree, id: ValueId, refcount: u64) -> Result<(), VaultError> {
+    if refcount == 0 {
+        refcounts_map.remove(id.as_bytes())?;
+    } else {
+        refcounts_map.insert(id.as_bytes(), bincode::serde::encode_to_vec(refcount, BINCODE_CONFIG)?)?;
+    }
+    Ok(())
+}
+
+// The index entries of a stored value, see `TypeVault::index_map`.
+fn index_keys(type_map: &TypeMap, data: &[u8], id: ValueId) -> Result<Vec<Vec<u8>>, VaultError> {
+    // Values that are not stored under a tag of their own are never indexed.
+    let Some(info) = own_type(type_map, data)? else { return Ok(vec![]) };
+    let (_, tag_len) = decode_tag(data)?;
+    (info.indexed_fields)(&data[tag_len..])?
+        .into_iter()
+        .map(|(field, value)| Ok([index_prefix(&data[..tag_len], field, &value)?, id.as_bytes().to_vec()].concat()))
+        .collect()
+}
+
+// The referrers entries of a stored value, see `TypeVault::referrers_map`.
+fn referrer_keys(type_map: &TypeMap, data: &[u8], id: ValueId) -> Result<Vec<Vec<u8>>, VaultError> {
+    Ok(child_ids(type_map, data)?.into_iter().map(|child| [child.as_bytes(), id.as_bytes()].concat()).collect())
+}
+
+// The type of a stored value, if it is stored under the tag of that type. Values in an `Option`
+// are stored behind its marker, and tuples as the facts of their elements one after the other,
+// which starts with the tag of the first element but is not a value of its type.
+fn own_type<'m>(type_map: &'m TypeMap, data: &[u8]) -> Result<Option<&'m TypeInfo>, VaultError> {
+    if data.first().is_none_or(|byte| u64::from(*byte) < FIRST_TYPE_TAG) {
+        return Ok(None);
+    }
+    let (tag, tag_len) = decode_tag(data)?;
+    let info = type_map.type_of_tag(tag).ok_or_else(|| VaultError::UnknownTypeTag(data[..tag_len].to_vec()))?;
+    let (_, bytes_consumed) = (info.remap_fact_ids)(&data[tag_len..], &mut Ok)?;
+    Ok(Some(info).filter(|_| tag_len + bytes_consumed == data.len()))
+}
+
+// The ids a stored value refers to directly, each once. Stored values are a single tagged fact, or
+// several for tuples, each possibly behind an `Option` marker.
+fn child_ids(type_map: &TypeMap, data: &[u8]) -> Result<Vec<ValueId>, VaultError> {
+    let mut children = vec![];
+    let mut rest = data;
+    while let Some(byte) = rest.first() {
+        if u64::from(*byte) < FIRST_TYPE_TAG {
+            rest = &rest[1..];
+            continue;
+        }
+        let (tag, tag_len) = decode_tag(rest)?;
+        let info = type_map.type_of_tag(tag).ok_or_else(|| VaultError::UnknownTypeTag(rest[..tag_len].to_vec()))?;
+        let (_, bytes_consumed) = (info.remap_fact_ids)(&rest[tag_len..], &mut |child| {
+            children.push(child);
+            Ok(child)
+        })?;
+        rest = &rest[tag_len + bytes_consumed..];
+    }
+    children.sort();
+    children.dedup();
+    Ok(children)
+}
+
+// The values of `serialized`, as returned by `serialize_type`, that `lookup` doesn't find, in the
+// same order. Values are stored by the hash of their contents, so whatever is nested in a value
+// that is found is stored too: only the values nested in ones that aren't found are looked up,
+// starting from the value itself. Fails with `VaultError::IdCollision` if `lookup` finds other
+// data under an id.
+//
+// This only saves the lookups and writes. Serializing a value takes time in proportion to its
+// size, except for the parts of it that are `Shared`, which `serialize_type_skipping` leaves out
+// when they are stored.
+fn unstored(type_map: &TypeMap, serialized: Vec<(Vec<u8>, ValueId)>, mut lookup: impl FnMut(ValueId) -> Result<Option<Vec<u8>>, VaultError>) -> Result<Vec<(Vec<u8>, ValueId)>, VaultError> {
+    let positions: HashMap<ValueId, usize> = serialized.iter().enumerate().map(|(position, (_val, id))| (*id, position)).collect();
+    let mut is_unstored = vec![false; serialized.len()];
+    let mut visited = HashSet::new();
+    let mut pending: Vec<usize> = serialized.len().checked_sub(1).into_iter().collect();
+    while let Some(position) = pending.pop() {
+        let (val, id) = &serialized[position];
+        if !visited.insert(*id) {
+            continue;
+        }
+        match lookup(*id)? {
+            Some(stored) if stored == *val => {},
+            Some(_) => return Err(VaultError::IdCollision(*id)),
+            None => {
+                is_unstored[position] = true;
+                pending.extend(child_ids(type_map, val)?.iter().filter_map(|child| positions.get(child)));
+            },
+        }
+    }
+    Ok(serialized.into_iter().zip(is_unstored).filter(|(_, is_unstored)| *is_unstored).map(|(value, _)| value).collect())
+}
+
+fn index_prefix(tag: &[u8], field: u64, value: &[u8]) -> Result<Vec<u8>, VaultError> {
+    Ok([tag, &bincode::serde::encode_to_vec(field, BINCODE_CONFIG)?, value].concat())
+}
+
+fn encode_bound<V: OrderedKey>(bound: Bound<&V>) -> Result<Bound<Vec<u8>>, VaultError> {
+    Ok(match bound {
+        Bound::Included(value) => Bound::Included(encode_key(value)?),
+        Bound::Excluded(value) => Bound::Excluded(encode_key(value)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+// The keys that start with `prefix` followed by an encoded field within `bounds`. All encodings of
+// a field have the same length, so the keys for one field value all start with the same bytes.
+fn key_range(prefix: &[u8], bounds: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let start = match &bounds.0 {
+        Bound::Included(value) => Bound::Included([prefix, value].concat()),
+        Bound::Excluded(value) => Bound::Included(prefix_end(&[prefix, value].concat())),
+        Bound::Unbounded => Bound::Included(prefix.to_vec()),
+    };
+    let end = match &bounds.1 {
+        Bound::Included(value) => Bound::Excluded(prefix_end(&[prefix, value].concat())),
+        Bound::Excluded(value) => Bound::Excluded([prefix, value].concat()),
+        Bound::Unbounded => Bound::Excluded(prefix_end(prefix)),
+    };
+    (start, end)
+}
+
+// The first key after every key that starts with `prefix`.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    unreachable!("keys start with a type tag, and no encoded tag starts with 0xff")
+}
+
+// Whether every write of `batch` was already made to `tree`.
+fn is_applied(tree: &impl VaultTree, batch: &Batch) -> Result<bool, VaultError> {
+    for (key, value) in batch.writes() {
+        if tree.get(key)?.as_deref() != value {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn id_from_bytes(bytes: &[u8]) -> Result<ValueId, VaultError> {
+    ValueId::from_slice(bytes)
+        .ok_or_else(|| VaultError::Corrupt(format!("stored id {:?} has the wrong length", bytes)))
+}
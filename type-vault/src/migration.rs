@@ -0,0 +1,290 @@
+use crate::{backend::TransactionTree, id_from_bytes, index_keys, own_type, referrer_keys, Batch, TypeVault, VaultBackend, VaultTree, REFCOUNTS_KEY};
+use type_vault_trait::*;
+use serde::de::DeserializeOwned;
+use std::{cell::RefCell, collections::HashMap};
+
+type Convert = dyn Fn(&[u8], &MigrationContext) -> Result<(Vec<(Vec<u8>, ValueId)>, usize), VaultError>;
+
+/// Conversions that bring the values of changed types up to date when a vault is opened, see
+/// `VaultOptions::migrations`.
+#[derive(Default)]
+pub struct Migrations {
+    conversions: Vec<Conversion>,
+}
+
+pub(crate) struct Conversion {
+    pub(crate) from_name: &'static str,
+    from_schema: Schema,
+    to_name: &'static str,
+    to_schema: Schema,
+    // Decodes an `Old::Fact` at the start of the data, converts it and serializes the result.
+    convert: Box<Convert>,
+}
+
+impl Conversion {
+    // Whether the stored values of the old type move to a type registered under another name.
+    pub(crate) fn renames(&self) -> bool {
+        self.from_name != self.to_name
+    }
+}
+
+impl Migrations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Converts values that were stored as `Old` into `New`. `Old` is the earlier definition of
+    /// the type, kept around under the same `#[vault(name = "...")]`, and the conversion only
+    /// applies to vaults whose stored schema matches it. Nested values are passed by their old
+    /// ids and are read with `MigrationContext::get`, which migrates them first.
+    ///
+    /// `New` may have another type name than `Old`, in which case the stored values of `Old` are
+    /// moved over to `New`.
+    pub fn convert<Old, New>(mut self, convert: impl Fn(Old::Fact, &MigrationContext) -> Result<New, VaultError> + 'static) -> Self
+    where
+        Old: VaultType,
+        Old::Fact: DeserializeOwned,
+        New: VaultType + 'static,
+    {
+        self.conversions.push(Conversion {
+            from_name: Old::TYPE_NAME,
+            from_schema: Old::schema(),
+            to_name: New::TYPE_NAME,
+            to_schema: New::schema(),
+            convert: Box::new(move |data, ctx| {
+                let (fact, bytes_consumed): (Old::Fact, _) = bincode::serde::decode_from_slice(data, BINCODE_CONFIG)?;
+                let value = convert(fact, ctx)?;
+                Ok((serialize_type(&value, ctx.migrator.type_map())?, bytes_consumed))
+            }),
+        });
+        self
+    }
+
+    // The conversions that apply to the stored types, by the tag of the type they convert from.
+    // A conversion applies when its old schema is the stored one and the new one is not.
+    pub(crate) fn pending(&self, stored_types: &HashMap<String, (u64, Schema)>) -> HashMap<u64, &Conversion> {
+        let mut pending = HashMap::new();
+        for conversion in &self.conversions {
+            let Some((tag, stored_schema)) = stored_types.get(conversion.from_name) else { continue };
+            let is_stored = conversion.from_schema.incompatibilities(stored_schema).is_empty();
+            let is_done = conversion.from_name == conversion.to_name
+                && conversion.to_schema.incompatibilities(stored_schema).is_empty();
+            if is_stored && !is_done {
+                pending.entry(*tag).or_insert(conversion);
+            }
+        }
+        pending
+    }
+}
+
+/// Gives conversions access to the values nested in the facts they convert.
+pub struct MigrationContext<'a> {
+    migrator: &'a Migrator<'a>,
+}
+
+impl MigrationContext<'_> {
+    /// Reads the value stored under the old id `id`, migrated to the current definitions.
+    pub fn get<T: VaultType>(&self, id: ValueId) -> Result<T, VaultError> {
+        let new_id = self.migrator.migrate(id)?;
+        let data = self.migrator.lookup_id(new_id)?.ok_or(VaultError::MissingId(id))?;
+        deserialize_type::<T>(&data, self.migrator.type_map(), &|id_needle| self.migrator.lookup_id(id_needle))
+    }
+}
+
+struct Migrator<'a> {
+    type_map: &'a TypeMap,
+    // Reads the values as they are stored, before the migration.
+    stored: &'a dyn Fn(ValueId) -> Result<Option<Vec<u8>>, VaultError>,
+    conversions: HashMap<u64, &'a Conversion>,
+    // The new id of every value migrated so far, by its old id.
+    new_ids: RefCell<HashMap<ValueId, ValueId>>,
+    // Values that are not stored yet, by their new id.
+    new_values: RefCell<HashMap<ValueId, Vec<u8>>>,
+}
+
+impl Migrator<'_> {
+    fn type_map(&self) -> &TypeMap {
+        self.type_map
+    }
+
+    fn lookup_id(&self, id: ValueId) -> Result<Option<Vec<u8>>, VaultError> {
+        match self.new_values.borrow().get(&id) {
+            Some(data) => Ok(Some(data.clone())),
+            None => (self.stored)(id),
+        }
+    }
+
+    // Migrates the value stored under `id` after everything it refers to, returning its new id.
+    fn migrate(&self, id: ValueId) -> Result<ValueId, VaultError> {
+        if let Some(new_id) = self.new_ids.borrow().get(&id) {
+            return Ok(*new_id);
+        }
+        let data = (self.stored)(id)?.ok_or(VaultError::MissingId(id))?;
+        let mut new_data = vec![];
+        let mut rest = data.as_slice();
+        while !rest.is_empty() {
+            rest = self.migrate_item(rest, &mut new_data)?;
+        }
+        let new_id = if new_data == data {
+            id
+        } else {
+            let new_id = self.type_map().value_id_of(&new_data);
+            self.new_values.borrow_mut().insert(new_id, new_data);
+            new_id
+        };
+        self.new_ids.borrow_mut().insert(id, new_id);
+        Ok(new_id)
+    }
+
+    // Migrates the item at the start of `data` into `dest` and returns what follows it. Stored
+    // values are a single tagged fact, or several for tuples, each possibly behind an `Option`
+    // marker.
+    fn migrate_item<'d>(&self, data: &'d [u8], dest: &mut Vec<u8>) -> Result<&'d [u8], VaultError> {
+        match data[0] {
+            0 => {
+                dest.push(0);
+                Ok(&data[1..])
+            },
+            1 => {
+                dest.push(1);
+                match &data[1..] {
+                    [] => Err(VaultError::Truncated("Option")),
+                    inner => self.migrate_item(inner, dest),
+                }
+            },
+            _ => {
+                let (tag, tag_len) = decode_tag(data)?;
+                let fact = &data[tag_len..];
+                if let Some(conversion) = self.conversions.get(&tag) {
+                    let (mut values, bytes_consumed) = (conversion.convert)(fact, &MigrationContext { migrator: self })?;
+                    let (value, _id) = values.pop().expect("serialize_type always returns the value itself");
+                    dest.extend(value);
+                    self.new_values.borrow_mut().extend(values.into_iter().map(|(value, id)| (id, value)));
+                    Ok(&fact[bytes_consumed..])
+                } else {
+                    let info = self.type_map().type_of_tag(tag)
+                        .ok_or_else(|| VaultError::UnknownTypeTag(data[..tag_len].to_vec()))?;
+                    let (new_fact, bytes_consumed) = (info.remap_fact_ids)(fact, &mut |id| self.migrate(id))?;
+                    dest.extend_from_slice(&data[..tag_len]);
+                    dest.extend(new_fact);
+                    Ok(&fact[bytes_consumed..])
+                }
+            },
+        }
+    }
+}
+
+// Rewrites every stored value of the converted types, and every value that refers to one of them
+// directly or indirectly, together with `registry_changes` in a single transaction. All other
+// values keep their ids, and roots are pointed at the new ids. The reference counts are recounted
+// afterwards.
+pub(crate) fn migrate<B: VaultBackend>(vault: &TypeVault<B>, conversions: HashMap<u64, &Conversion>, registry_changes: Batch) -> Result<(), VaultError> {
+    vault.check_writable()?;
+    let migrator = Migrator {
+        type_map: &vault.type_map,
+        stored: &|id| vault.lookup_id(id),
+        conversions,
+        new_ids: RefCell::default(),
+        new_values: RefCell::default(),
+    };
+    for entry in vault.id_to_value_map.iter() {
+        let (id_bytes, _data) = entry?;
+        migrator.migrate(id_from_bytes(&id_bytes)?)?;
+    }
+    let new_ids = migrator.new_ids.into_inner();
+    let new_values = migrator.new_values.into_inner();
+    let mut new_index_keys = vec![];
+    let mut new_referrer_keys = vec![];
+    for (id, data) in &new_values {
+        new_index_keys.extend(index_keys(&vault.type_map, data, *id)?);
+        new_referrer_keys.extend(referrer_keys(&vault.type_map, data, *id)?);
+    }
+    // The old values may not decode with the current definitions, so their index and referrers
+    // entries are found by the id they end with.
+    let id_width = vault.type_map.id_width();
+    let is_old = |key: &[u8]| -> Result<bool, VaultError> {
+        let id = id_from_bytes(&key[key.len().saturating_sub(id_width)..])?;
+        Ok(new_ids.get(&id).is_some_and(|new_id| *new_id != id))
+    };
+    let mut old_index_keys = vec![];
+    for entry in vault.index_map.iter() {
+        let (key, _) = entry?;
+        if is_old(&key)? {
+            old_index_keys.push(key);
+        }
+    }
+    let mut old_referrer_keys = vec![];
+    for entry in vault.referrers_map.iter() {
+        let (key, _) = entry?;
+        if is_old(&key)? {
+            old_referrer_keys.push(key);
+        }
+    }
+    // Roots and their history point at the new ids from now on.
+    let moved = |tree: &B::Tree| -> Result<Vec<(Vec<u8>, ValueId)>, VaultError> {
+        let mut moved = vec![];
+        for entry in tree.iter() {
+            let (key, id_bytes) = entry?;
+            let id = id_from_bytes(&id_bytes)?;
+            match new_ids.get(&id) {
+                Some(new_id) if *new_id != id => moved.push((key, *new_id)),
+                _ => {},
+            }
+        }
+        Ok(moved)
+    };
+    let moved_roots = moved(&vault.roots_map)?;
+    let moved_history = moved(&vault.root_history_map)?;
+    let trees = [
+        &vault.id_to_value_map, &vault.value_to_id_map, &vault.index_map, &vault.referrers_map, &vault.meta_map,
+        &vault.roots_map, &vault.root_history_map, &vault.change_log_map, &vault.change_log_keys_map,
+    ];
+    vault.backend.transaction(&trees, &|tx| {
+        let [id_to_value_map, value_to_id_map, index_map, referrers_map, meta_map, roots_map, root_history_map, change_log_map, change_log_keys_map] =
+            [0, 1, 2, 3, 4, 5, 6, 7, 8].map(|tree| TransactionTree { tx, tree });
+        // Remove the old values with their index and referrers entries first, as a new value may
+        // happen to be stored under the id of a value that was converted.
+        for (id, new_id) in &new_ids {
+            if id != new_id {
+                if let Some(data) = id_to_value_map.remove(id.as_bytes())? {
+                    value_to_id_map.remove(data)?;
+                }
+                if let Some(key) = change_log_keys_map.remove(id.as_bytes())? {
+                    change_log_map.remove(key)?;
+                }
+            }
+        }
+        for key in &old_index_keys {
+            index_map.remove(key)?;
+        }
+        for key in &old_referrer_keys {
+            referrers_map.remove(key)?;
+        }
+        for (id, data) in &new_values {
+            value_to_id_map.insert(data.as_slice(), id.as_bytes())?;
+            id_to_value_map.insert(id.as_bytes(), data.as_slice())?;
+            // Converted values are logged as new values of their type.
+            if own_type(&vault.type_map, data)?.is_some() && change_log_keys_map.get(id)?.is_none() {
+                let (_, tag_len) = decode_tag(data)?;
+                let key = [&data[..tag_len], &tx.generate_id()?.to_be_bytes()].concat();
+                change_log_map.insert(&key, id.as_bytes())?;
+                change_log_keys_map.insert(id.as_bytes(), &key)?;
+            }
+        }
+        for key in &new_index_keys {
+            index_map.insert(key.as_slice(), [])?;
+        }
+        for key in &new_referrer_keys {
+            referrers_map.insert(key.as_slice(), [])?;
+        }
+        for (key, new_id) in &moved_roots {
+            roots_map.insert(key.as_slice(), new_id.as_bytes())?;
+        }
+        for (key, new_id) in &moved_history {
+            root_history_map.insert(key.as_slice(), new_id.as_bytes())?;
+        }
+        meta_map.apply_batch(&registry_changes)?;
+        meta_map.remove(REFCOUNTS_KEY)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,78 @@
+use crate::{backend::TransactionTree, id_from_bytes, refcount, set_refcount, TypeVault, VaultBackend, VaultTree};
+use type_vault_trait::*;
+
+impl<B: VaultBackend> TypeVault<B> {
+    /// Points the root `name` at the value stored under `id`, whatever it pointed at before.
+    /// Fails with `VaultError::MissingId` if there is no such value. The value can't be removed
+    /// while a root points at it, see `VaultTransaction::remove`.
+    pub fn set_root(&self, name: &str, id: ValueId) -> Result<(), VaultError> {
+        self.write_root(name, id, |_| true).map(|_| ())
+    }
+
+    /// Points the root `name` at the value stored under `new`, but only if it still points at
+    /// `expected`, or doesn't exist yet if that is `None`. Returns whether it did.
+    pub fn update_root(&self, name: &str, expected: Option<ValueId>, new: ValueId) -> Result<bool, VaultError> {
+        self.write_root(name, new, |current| current == expected.as_ref().map(ValueId::as_bytes))
+    }
+
+    /// The id the root `name` points at, or `None` if it was never set.
+    pub fn root_id(&self, name: &str) -> Result<Option<ValueId>, VaultError> {
+        match self.roots_map.get(name)? {
+            Some(id_bytes) => id_from_bytes(&id_bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads back the value the root `name` points at, or `None` if it was never set.
+    pub fn get_root<T: VaultType>(&self, name: &str) -> Result<Option<T>, VaultError> {
+        match self.root_id(name)? {
+            Some(id) => Ok(Some(self.get(id)?.ok_or(VaultError::MissingId(id))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The ids the root `name` was pointed at, oldest first and ending with the current one.
+    pub fn root_history(&self, name: &str) -> Result<impl Iterator<Item = Result<ValueId, VaultError>> + '_, VaultError> {
+        let prefix = bincode::serde::encode_to_vec(name, BINCODE_CONFIG)?;
+        Ok(self.root_history_map.scan_prefix(prefix).map(|entry| id_from_bytes(&entry?.1)))
+    }
+
+    /// The names of all roots with the ids they point at, ordered by name.
+    pub fn roots(&self) -> impl Iterator<Item = Result<(String, ValueId), VaultError>> + '_ {
+        self.roots_map.iter().map(|entry| {
+            let (name, id_bytes) = entry?;
+            Ok((String::from_utf8_lossy(&name).into_owned(), id_from_bytes(&id_bytes)?))
+        })
+    }
+
+    // Points the root at `id` and records it in its history, if `is_expected` accepts the id it
+    // points at now.
+    fn write_root(&self, name: &str, id: ValueId, is_expected: impl Fn(Option<&[u8]>) -> bool) -> Result<bool, VaultError> {
+        self.check_writable()?;
+        // Names are encoded with their length, so that no name is a prefix of another.
+        let encoded_name = bincode::serde::encode_to_vec(name, BINCODE_CONFIG)?;
+        let trees = [&self.roots_map, &self.root_history_map, &self.id_to_value_map, &self.refcounts_map];
+        self.backend.transaction(&trees, &|tx| {
+            let [roots_map, root_history_map, id_to_value_map, refcounts_map] = [0, 1, 2, 3].map(|tree| TransactionTree { tx, tree });
+            if id_to_value_map.get(id)?.is_none() {
+                return Err(VaultError::MissingId(id));
+            }
+            let current = roots_map.get(name)?;
+            if !is_expected(current.as_deref()) {
+                return Ok(false);
+            }
+            // Roots count as references to what they point at, so that it isn't removed.
+            if self.reference_counts {
+                if let Some(current) = current {
+                    let current = id_from_bytes(&current)?;
+                    set_refcount(&refcounts_map, current, refcount(&refcounts_map, current)?.saturating_sub(1))?;
+                }
+                set_refcount(&refcounts_map, id, refcount(&refcounts_map, id)? + 1)?;
+            }
+            let history_key = [encoded_name.as_slice(), &tx.generate_id()?.to_be_bytes()].concat();
+            roots_map.insert(name, id.as_bytes())?;
+            root_history_map.insert(history_key, id.as_bytes())?;
+            Ok(true)
+        })
+    }
+}
@@ -0,0 +1,116 @@
+use type_vault_trait::*;
+use type_vault_trait_derive::VaultType;
+
+use serde::{Deserialize, Serialize};
+use type_vault::new_type_vault;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+// Set to the path of the vault in the child process.
+const WRITER_ENV: &str = "TYPE_VAULT_CRASH_WRITER";
+const ROOTS_PER_BATCH: u32 = 8;
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Leaf {
+    batch: u32,
+    slot: u32,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Node {
+    batch: u32,
+    slot: u32,
+    left: Box<Leaf>,
+    right: Box<Leaf>,
+}
+
+fn node(batch: u32, slot: u32) -> Node {
+    Node {
+        batch,
+        slot,
+        left: Box::new(Leaf { batch, slot: 2 * slot }),
+        right: Box::new(Leaf { batch, slot: 2 * slot + 1 }),
+    }
+}
+
+// Runs in the child process: commits batches of nested values, and once enough of them had the
+// time to reach the disk, stops halfway through a batch and tells the parent to kill it there.
+fn run_writer(path: &Path) -> ! {
+    let db = new_type_vault!(path, Node, Leaf).unwrap();
+    let started = Instant::now();
+    for batch in 0.. {
+        db.transaction(|tx| {
+            for slot in 0..ROOTS_PER_BATCH {
+                if slot == ROOTS_PER_BATCH / 2 && batch >= 100 && started.elapsed() >= Duration::from_millis(1500) {
+                    println!("inside batch {}", batch);
+                    // The parent never answers, it kills the writer instead.
+                    std::io::stdin().read_line(&mut String::new()).unwrap();
+                }
+                tx.put(&node(batch, slot))?;
+            }
+            Ok(())
+        }).unwrap();
+        println!("committed batch {}", batch);
+    }
+    unreachable!()
+}
+
+#[test]
+fn test_crash_consistency() {
+    if let Some(path) = std::env::var_os(WRITER_ENV) {
+        run_writer(Path::new(&path));
+    }
+
+    // A directory of its own, so that runs don't get in each other's way, which is deleted once
+    // the test is done, whether it passes or not.
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("crash_test_db");
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .args(["test_crash_consistency", "--exact", "--nocapture"])
+        .env(WRITER_ENV, &path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Kill the writer without giving it any chance to clean up as soon as it is inside a
+    // transaction that it hasn't committed. The test harness can print on the same line as the
+    // first batch.
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut committed = None;
+    let interrupted = loop {
+        match lines.next() {
+            Some(line) => {
+                let line = line.unwrap();
+                if let Some((_, batch)) = line.split_once("inside batch ") {
+                    break batch.parse::<u32>().unwrap();
+                } else if let Some((_, batch)) = line.split_once("committed batch ") {
+                    committed = Some(batch.parse::<u32>().unwrap());
+                }
+            }
+            None => panic!("Writer exited before it was killed"),
+        }
+    };
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert_eq!(committed.map(|batch| batch + 1), Some(interrupted), "The writer was not killed inside a transaction");
+
+    // Every stored root must decode, which requires all of its nested values to be present, and
+    // every batch must be there either completely or not at all.
+    let db = new_type_vault!(&path, Node, Leaf).unwrap();
+    let mut roots_per_batch: HashMap<u32, u32> = HashMap::new();
+    for result in db.scan_all::<Node>().unwrap() {
+        let (value, _id) = result.unwrap();
+        assert_eq!(*value, node(value.batch, value.slot));
+        *roots_per_batch.entry(value.batch).or_default() += 1;
+    }
+    assert!(!roots_per_batch.is_empty(), "No batch reached the disk before the writer was killed");
+    assert!(!roots_per_batch.contains_key(&interrupted), "Batch {} was stored without being committed", interrupted);
+    for (batch, roots) in roots_per_batch {
+        assert_eq!(roots, ROOTS_PER_BATCH, "Batch {} was only partially stored", batch);
+    }
+}
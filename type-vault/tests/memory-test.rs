@@ -0,0 +1,91 @@
+use type_vault_trait::*;
+use type_vault_trait_derive::VaultType;
+
+use serde::{Deserialize, Serialize};
+use type_vault::TypeVault;
+use type_vault::MemoryBackend as Backend;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+const BACKEND: &str = "memory";
+
+mod suite;
+
+// The backends of the vaults the suite opens, by their path, so that they can be opened again.
+static VAULTS: Mutex<Option<HashMap<PathBuf, Backend>>> = Mutex::new(None);
+
+fn open_backend(path: &Path) -> Result<Backend, VaultError> {
+    let mut vaults = VAULTS.lock().unwrap_or_else(PoisonError::into_inner);
+    Ok(vaults.get_or_insert_with(HashMap::new).entry(path.to_owned()).or_default().clone())
+}
+
+fn remove_vault(path: &Path) {
+    let mut vaults = VAULTS.lock().unwrap_or_else(PoisonError::into_inner);
+    vaults.get_or_insert_with(HashMap::new).remove(path);
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Leaf {
+    value: u32,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Node {
+    #[vault(index)]
+    label: u32,
+    left: Box<Leaf>,
+    right: Box<Leaf>,
+}
+
+fn node(label: u32) -> Node {
+    Node { label, left: Box::new(Leaf { value: 2 * label }), right: Box::new(Leaf { value: 2 * label + 1 }) }
+}
+
+fn open() -> TypeVault<type_vault::MemoryBackend> {
+    TypeVault::in_memory(vec![TypeInfo::of::<Node>(), TypeInfo::of::<Leaf>()]).unwrap()
+}
+
+#[test]
+fn test_in_memory() {
+    // Every vault is a store of its own, so they can be used side by side.
+    let handles: Vec<_> = (0..4).map(|thread| std::thread::spawn(move || {
+        let db = open();
+        let ids: Vec<ValueId> = (0..50).map(|label| db.put(&node(thread * 100 + label)).unwrap()).collect();
+        // Shared nested values are only stored once.
+        assert_eq!(db.put(&node(thread * 100)).unwrap(), ids[0]);
+        assert_eq!(db.count::<Node>().unwrap(), 50);
+        assert_eq!(db.count::<Leaf>().unwrap(), 100);
+        assert_eq!(db.get::<Node>(ids[7]).unwrap(), Some(node(thread * 100 + 7)));
+        let found: Vec<ValueId> = db.query(&Node::query().label(thread * 100 + 7)).unwrap().map(|res| res.unwrap().1).collect();
+        assert_eq!(found, vec![ids[7]]);
+    })).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn test_in_memory_transactions() {
+    let db = open();
+    let mut subscription = db.subscribe::<Node>().unwrap();
+
+    // Nothing put in a transaction that fails is stored, or seen by subscriptions.
+    let failed = db.transaction(|tx| {
+        tx.put(&node(1))?;
+        Err::<(), _>(VaultError::Unsupported("giving up"))
+    });
+    assert!(matches!(failed, Err(VaultError::Unsupported("giving up"))));
+    assert_eq!(db.count::<Leaf>().unwrap(), 0);
+    assert!(subscription.next_timeout(std::time::Duration::from_millis(50)).is_none());
+
+    let ids = db.transaction(|tx| Ok((tx.put(&node(1))?, tx.put(&node(2))?))).unwrap();
+    assert_eq!(subscription.next().unwrap().unwrap().id, ids.0);
+    assert_eq!(subscription.next().unwrap().unwrap().id, ids.1);
+
+    // Removing a value removes what is nested only in it.
+    assert!(db.remove(ids.0).unwrap());
+    assert_eq!(db.count::<Leaf>().unwrap(), 2);
+    assert_eq!(db.gc([], false).unwrap().orphans, 3);
+    assert_eq!(db.count::<Leaf>().unwrap(), 0);
+}
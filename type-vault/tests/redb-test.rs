@@ -0,0 +1,14 @@
+use type_vault::RedbBackend as Backend;
+
+const BACKEND: &str = "redb";
+
+mod suite;
+
+fn open_backend(path: &std::path::Path) -> Result<Backend, type_vault_trait::VaultError> {
+    Backend::open(path)
+}
+
+// redb keeps a vault in a single file.
+fn remove_vault(path: &std::path::Path) {
+    let _ = std::fs::remove_file(path);
+}
@@ -0,0 +1,1252 @@
+use type_vault_trait::*;
+use type_vault_trait_derive::VaultType;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::ops::Bound;
+use type_vault::{AccessPath, Change, GcReport, Migrations, Program, Rule, Term, TypeVault, VaultBackend, VaultOptions, VaultTree}; // Import FactDB from the appropriate crate
+use super::{open_backend, remove_vault, Backend, BACKEND};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct BaseStruct {
+    foo: u32,
+}
+
+// Later versions of BaseStruct and TestStruct, with changes to their fields.
+#[derive(VaultType, Debug, PartialEq, Clone)]
+#[vault(name = "BaseStruct")]
+struct BaseStructV2 {
+    foo: u64,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+#[vault(name = "BaseStruct")]
+struct RenamedBaseStruct {
+    bar: u32,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+#[vault(name = "TestStruct")]
+struct TestStructV2 {
+    field: u32,
+    base_field: Box<BaseStructV2>,
+    rec_field: u64,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Span {
+    start: u32,
+    end: u32,
+}
+
+// A later version of Span, with the same fields in another order.
+#[derive(VaultType, Debug, PartialEq, Clone)]
+#[vault(name = "Span")]
+struct SwappedSpan {
+    end: u32,
+    start: u32,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct TestStruct {
+    field: u32,
+    base_field: Box<BaseStruct>,
+    rec_field: Option<Box<TestStruct>>,
+}
+
+// Address and Person as they were first stored, and Household, which refers to them but never
+// changed itself.
+#[derive(VaultType, Debug, PartialEq, Clone)]
+#[vault(name = "Address")]
+struct AddressV1 {
+    number: u32,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+#[vault(name = "Person")]
+struct PersonV1 {
+    age: u32,
+    address: Box<AddressV1>,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+#[vault(name = "Household")]
+struct HouseholdV1 {
+    size: u32,
+    head: Box<PersonV1>,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Address {
+    number: u32,
+    floor: u32,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Person {
+    age: u32,
+    address: Box<Address>,
+    verified: bool,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Household {
+    size: u32,
+    head: Box<Person>,
+}
+
+// Reading before any of its fields were indexed.
+#[derive(VaultType, Debug, PartialEq, Clone)]
+#[vault(name = "Reading")]
+struct UnindexedReading {
+    sensor: u32,
+    value: u32,
+    base: Box<BaseStruct>,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Reading {
+    sensor: u32,
+    #[vault(index)]
+    value: u32,
+    #[vault(index)]
+    base: Box<BaseStruct>,
+}
+
+// Stored as the facts of both readings one after the other, which starts with the tag of
+// `Reading`.
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Pair {
+    pair: (Reading, Reading),
+}
+
+// Leads with a field stored by id, so its values are ordered by the id of their base.
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Labelled {
+    base: Box<BaseStruct>,
+    label: u32,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Measurement {
+    offset: i32,
+    #[vault(index)]
+    celsius: f64,
+    count: u16,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct DependsOn {
+    package: u32,
+    dependency: u32,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct Requires {
+    package: u32,
+    dependency: u32,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+struct HeadAge {
+    size: u32,
+    age: u32,
+}
+
+#[derive(VaultType, Debug, PartialEq, Clone)]
+enum Tree {
+    Leaf(u32),
+    Node(Shared<Tree>, Shared<Tree>),
+}
+
+// The path of a vault in a temporary directory, which is deleted together with the vault once
+// the test is done, whether it passes or not.
+struct TestPath {
+    path: PathBuf,
+    _dir: TempDir,
+}
+
+impl std::ops::Deref for TestPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestPath {
+    fn drop(&mut self) {
+        remove_vault(&self.path);
+    }
+}
+
+// The tests run once for every backend, each with vaults of its own.
+fn test_path(name: &str) -> TestPath {
+    let dir = tempfile::Builder::new().prefix(&format!("{}_{}_", name, BACKEND)).tempdir().unwrap();
+    TestPath { path: dir.path().join(name), _dir: dir }
+}
+
+fn open_vault(path: &Path, types: Vec<TypeInfo>, options: VaultOptions) -> Result<TypeVault<Backend>, VaultError> {
+    TypeVault::with_backend(open_backend(path)?, types, options)
+}
+
+// The number of entries in the tree `name` of the vault at `path`, which must not be open.
+fn tree_len(path: &Path, name: &str) -> usize {
+    for _ in 0..100 {
+        match open_backend(path) {
+            Ok(backend) => return VaultTree::iter(&backend.open_tree(name).unwrap()).count(),
+            Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+        }
+    }
+    panic!("Could not open the vault at {}", path.display())
+}
+
+macro_rules! new_vault {
+    ($e:expr, $($tys:ty),+) => {
+        open_vault($e, vec![$(TypeInfo::of::<$tys>()),+], VaultOptions::new())
+    };
+}
+
+// sled lets go of its lock on the vault directory in a background thread after the vault is
+// dropped, so opening it again right away can fail for a moment.
+fn open_again(open: impl Fn() -> Result<TypeVault<Backend>, VaultError>) -> Result<TypeVault<Backend>, VaultError> {
+    for _ in 0..100 {
+        match open() {
+            Err(VaultError::Storage(err)) if err.to_string().contains("could not acquire lock") =>
+                std::thread::sleep(std::time::Duration::from_millis(10)),
+            result => return result,
+        }
+    }
+    open()
+}
+
+// The values `test_db_storage` stores, which the tests of lookups and scans store too.
+fn test_structs() -> (TestStruct, TestStruct, TestStruct) {
+    let struct1 = TestStruct { field: 42, base_field: Box::new(BaseStruct { foo: 10 }), rec_field : None };
+    let struct2 = TestStruct { field: 42, base_field: Box::new(BaseStruct { foo: 20 }), rec_field : None };
+    let struct3 = TestStruct { field: 43, base_field: Box::new(BaseStruct { foo: 10 }), rec_field : Some(Box::new(struct1.clone())) };
+    (struct1, struct2, struct3)
+}
+
+#[test]
+fn test_db_storage() {
+    // Test structs
+    let (struct1, struct2, struct3) = test_structs();
+
+    // Set up DB.
+    let path = &test_path("test_db");
+    let db = new_vault!(path, TestStruct, BaseStruct).unwrap();
+    db.clear().unwrap();
+    db.put(&struct1).unwrap();
+    db.put(&struct2).unwrap();
+    db.put(&struct3).unwrap();
+    db.debug_print().unwrap();
+    // The prefix starts with the type tag of TestStruct, followed by 42u32, the value of the `field` field.
+    let mut prefix = db.type_map.tag_of::<TestStruct>().unwrap();
+    prefix.extend(encode_key(&42u32).unwrap());
+    let mut visited = 0;
+    db.debug_scan_primitive(prefix).map(Result::unwrap).for_each(|(value, id) | {
+        println!("Scanned Value with ID {:?}: {:?}", id, value);
+        visited += 1;
+    });
+    assert_eq!(visited, 2); // At least struct1 and struct2 should match
+
+    // Roundtripping
+    let serialized: Vec<(Vec<u8>, ValueId)> = serialize_type(& struct1, &db.type_map).unwrap();
+    let lookup_id = |id| {
+        for (vec, hash) in serialized.iter() {
+            if *hash == id {
+                return Ok(Some(vec.clone()));
+            }
+        }
+        Ok(None)
+    };
+    let round_trip: Result<TestStruct, VaultError> =
+      deserialize_type(&serialized[2].0, &db.type_map, &lookup_id);
+    match round_trip {
+        Ok(deserialized) => {
+            // Check that the deserialized instance matches the original
+            assert_eq!(deserialized.field, struct1.field);
+            assert_eq!(deserialized.base_field.foo, struct1.base_field.foo);
+            println!("Roundtripped successfully: {:?}", deserialized);
+        },
+        Err(err) => panic!("Roundtripping failed: {}", err),
+    }
+
+    // Full prefix scan test
+    let scan_result: Vec<(Box<TestStruct>, ValueId)> =
+      db.scan(TestStruct { field: 42, base_field: Box::new(BaseStruct { foo: 0 }), rec_field : None }, 1).unwrap().map(Result::unwrap).collect();
+    let scanned = scan_result.into_iter().map(|(value, _id)| *value).collect::<Vec<TestStruct>>();
+    // Rows come back in key order, which depends on the ids of the nested values.
+    assert_eq!(scanned.len(), 2);
+    assert!(scanned.contains(&struct1) && scanned.contains(&struct2));
+    let scan_result2: Vec<(Box<TestStruct>, ValueId)> =
+      db.scan(TestStruct { field: 43, base_field: Box::new(BaseStruct { foo: 10 }), rec_field : None }, 2).unwrap().map(Result::unwrap).collect();
+    assert_eq!(scan_result2.into_iter().map(|(value, _id)| *value).collect::<Vec<TestStruct>>()
+      , vec![struct3]);
+
+}
+
+#[test]
+fn test_get() {
+    let (struct1, _, struct3) = test_structs();
+    let path = &test_path("test_db_get");
+    let db = new_vault!(path, TestStruct, BaseStruct).unwrap();
+    let id1 = db.put(&struct1).unwrap();
+    let id3 = db.put(&struct3).unwrap();
+
+    // Point lookups by the ids returned from put
+    assert_eq!(db.get::<TestStruct>(id1).unwrap(), Some(struct1.clone()));
+    assert_eq!(db.get::<TestStruct>(id3).unwrap(), Some(struct3.clone()));
+    assert_eq!(db.get::<TestStruct>(ValueId::from_slice(&[0xff; DEFAULT_ID_WIDTH]).unwrap()).unwrap(), None);
+}
+
+#[test]
+fn test_wrong_type() {
+    let path = &test_path("test_db_wrong_type");
+    let db = new_vault!(path, TestStruct, BaseStruct).unwrap();
+    let base_id = db.put(&BaseStruct { foo: 10 }).unwrap();
+
+    // Looking a value up as another type than it was stored as is an error.
+    assert!(matches!(db.get::<TestStruct>(base_id), Err(VaultError::WrongType { expected: "TestStruct", found: "BaseStruct" })));
+}
+
+#[test]
+fn test_scan_all_count_exists() {
+    let (struct1, struct2, struct3) = test_structs();
+    let path = &test_path("test_db_scan_all");
+    let db = new_vault!(path, TestStruct, BaseStruct).unwrap();
+    db.put(&struct1).unwrap();
+    db.put(&struct2).unwrap();
+    db.put(&struct3).unwrap();
+
+    // Listing and counting values by their type alone.
+    let mut all: Vec<TestStruct> = db.scan_all::<TestStruct>().unwrap().map(|result| *result.unwrap().0).collect();
+    all.sort_by_key(|value| (value.field, value.base_field.foo));
+    assert_eq!(all, vec![struct1, struct2, struct3]);
+    assert_eq!(db.count::<TestStruct>().unwrap(), 3);
+    assert_eq!(db.count::<BaseStruct>().unwrap(), 2);
+    assert!(db.exists::<BaseStruct>().unwrap());
+    db.clear().unwrap();
+    assert!(!db.exists::<TestStruct>().unwrap());
+    assert_eq!(db.scan_all::<TestStruct>().unwrap().count(), 0);
+}
+
+#[test]
+fn test_query_builder() {
+    let (struct1, struct2, struct3) = test_structs();
+    let path = &test_path("test_db_query_builder");
+    let db = new_vault!(path, TestStruct, BaseStruct).unwrap();
+    db.put(&struct1).unwrap();
+    db.put(&struct2).unwrap();
+    let id3 = db.put(&struct3).unwrap();
+
+    // Queries constrain fields by name, whether they lead the encoding or not.
+    let query_fields = |query: &TestStructQuery| -> Vec<(u32, u32)> {
+        let mut found: Vec<(u32, u32)> = db.query(query).unwrap()
+            .map(|result| result.unwrap().0)
+            .map(|value| (value.field, value.base_field.foo))
+            .collect();
+        found.sort();
+        found
+    };
+    assert_eq!(query_fields(&TestStruct::query()), vec![(42, 10), (42, 20), (43, 10)]);
+    assert_eq!(query_fields(&TestStruct::query().field(42)), vec![(42, 10), (42, 20)]);
+    let base = BaseStruct { foo: 10 };
+    assert_eq!(query_fields(&TestStruct::query().base_field(&base)), vec![(42, 10), (43, 10)]);
+    assert_eq!(query_fields(&TestStruct::query().base_field(&base).field(43)), vec![(43, 10)]);
+    let recursive = Some(Box::new(struct1.clone()));
+    assert_eq!(query_fields(&TestStruct::query().rec_field(&recursive)), vec![(43, 10)]);
+    assert_eq!(query_fields(&TestStruct::query().field(44)), vec![]);
+    let (_, nested_id) = db.query(&TestStruct::query().field(43)).unwrap().next().unwrap().unwrap();
+    assert_eq!(nested_id, id3);
+    let base_id = db.put(&base).unwrap();
+    assert_eq!(query_fields(&TestStruct::query().base_field_id(base_id)), vec![(42, 10), (43, 10)]);
+}
+
+#[test]
+fn test_scan_range() {
+    let (struct1, struct2, struct3) = test_structs();
+    let path = &test_path("test_db_scan_range");
+    let db = new_vault!(path, TestStruct, BaseStruct).unwrap();
+    db.put(&struct1).unwrap();
+    db.put(&struct2).unwrap();
+    db.put(&struct3).unwrap();
+
+    // Range scans return the values in the order of the field.
+    let in_range: Vec<u32> = db.scan_range::<TestStruct, u32>("field", 10..100).unwrap().map(|result| result.unwrap().0.field).collect();
+    assert_eq!(in_range, vec![42, 42, 43]);
+    assert_eq!(db.scan_range::<TestStruct, u32>("field", 43..).unwrap().count(), 1);
+}
+
+struct ReversedHasher;
+
+impl ContentHasher for ReversedHasher {
+    fn name(&self) -> &'static str {
+        "reversed-blake3"
+    }
+
+    fn hash_into(&self, data: &[u8], out: &mut [u8]) {
+        Blake3Hasher.hash_into(data, out);
+        out.reverse();
+    }
+}
+
+// Only looks at the length of the data, so different values of the same length collide.
+struct LengthHasher;
+
+impl ContentHasher for LengthHasher {
+    fn name(&self) -> &'static str {
+        "length"
+    }
+
+    fn hash_into(&self, data: &[u8], out: &mut [u8]) {
+        out.fill(0);
+        out[0] = data.len() as u8;
+    }
+}
+
+#[test]
+fn test_hasher_is_recorded() {
+    let path = &test_path("test_db_hasher");
+    {
+        let db = open_again(|| open_vault(path, vec![TypeInfo::of::<BaseStruct>()], VaultOptions::new().hasher(ReversedHasher))).unwrap();
+        db.put(&BaseStruct { foo: 1 }).unwrap();
+    }
+    // Reopening with the same hasher works, any other hasher is refused.
+    drop(open_again(|| open_vault(path, vec![TypeInfo::of::<BaseStruct>()], VaultOptions::new().hasher(ReversedHasher))).unwrap());
+    match open_again(|| new_vault!(path, BaseStruct)) {
+        Err(VaultError::HasherMismatch { stored, configured }) => {
+            assert_eq!(stored, "reversed-blake3");
+            assert_eq!(configured, "blake3");
+        },
+        _ => panic!("Opening with a different hasher should fail"),
+    }
+}
+
+#[test]
+fn test_id_width() {
+    let path = &test_path("test_db_id_width");
+    {
+        let db = open_again(|| open_vault(path, vec![TypeInfo::of::<BaseStruct>()], VaultOptions::new().id_width(32))).unwrap();
+        let id = db.put(&BaseStruct { foo: 1 }).unwrap();
+        assert_eq!(id.width(), 32);
+        assert_eq!(db.get::<BaseStruct>(id).unwrap(), Some(BaseStruct { foo: 1 }));
+    }
+    match open_again(|| new_vault!(path, BaseStruct)) {
+        Err(VaultError::IdWidthMismatch { stored, configured }) => {
+            assert_eq!(stored, 32);
+            assert_eq!(configured, DEFAULT_ID_WIDTH);
+        },
+        _ => panic!("Opening with a different id width should fail"),
+    }
+    assert!(matches!(
+        open_again(|| open_vault(path, vec![], VaultOptions::new().id_width(4))),
+        Err(VaultError::Unsupported(_))));
+}
+
+#[test]
+fn test_collisions_are_detected() {
+    let path = &test_path("test_db_collisions");
+    let db = open_again(|| open_vault(path, vec![TypeInfo::of::<BaseStruct>()], VaultOptions::new().hasher(LengthHasher))).unwrap();
+    let id = db.put(&BaseStruct { foo: 1 }).unwrap();
+    // Storing the same value again is fine, a different value with the same id is not.
+    assert_eq!(db.put(&BaseStruct { foo: 1 }).unwrap(), id);
+    match db.put(&BaseStruct { foo: 2 }) {
+        Err(VaultError::IdCollision(collided)) => assert_eq!(collided, id),
+        _ => panic!("Expected a collision"),
+    }
+    assert_eq!(db.get::<BaseStruct>(id).unwrap(), Some(BaseStruct { foo: 1 }));
+}
+
+#[test]
+fn test_type_registry() {
+    let path = &test_path("test_db_registry");
+    let value = TestStruct { field: 1, base_field: Box::new(BaseStruct { foo: 2 }), rec_field: None };
+    let (id, base_id) = {
+        let db = open_again(|| new_vault!(path, TestStruct, BaseStruct)).unwrap();
+        (db.put(&value).unwrap(), db.put(&BaseStruct { foo: 2 }).unwrap())
+    };
+    // Tags are looked up by type name, so the order types are registered in doesn't matter.
+    {
+        let db = open_again(|| new_vault!(path, BaseStruct, TestStruct)).unwrap();
+        assert_eq!(db.get::<TestStruct>(id).unwrap(), Some(value.clone()));
+        assert_eq!(db.put(&value).unwrap(), id);
+    }
+    match open_again(|| new_vault!(path, TestStructV2, BaseStructV2)) {
+        Err(VaultError::SchemaMismatch(mismatches)) => {
+            assert_eq!(mismatches, vec![
+                SchemaMismatch {
+                    type_name: "TestStruct",
+                    differences: vec![
+                        "field `rec_field` changed from being stored by id to inline".to_owned(),
+                    ],
+                },
+                SchemaMismatch {
+                    type_name: "BaseStruct",
+                    differences: vec!["field `foo` changed type from `u32` to `u64`".to_owned()],
+                },
+            ]);
+        },
+        other => panic!("Opening with changed schemas should fail: {:?}", other.err()),
+    }
+    // Renaming fields keeps the stored encoding intact.
+    {
+        let db = open_again(|| new_vault!(path, TestStruct, RenamedBaseStruct)).unwrap();
+        assert_eq!(db.get::<RenamedBaseStruct>(base_id).unwrap(), Some(RenamedBaseStruct { bar: 2 }));
+    }
+    assert!(matches!(open_again(|| new_vault!(path, BaseStruct, BaseStructV2)), Err(VaultError::DuplicateTypeName("BaseStruct"))));
+}
+
+#[test]
+fn test_reordered_fields() {
+    let path = &test_path("test_db_reordered");
+    {
+        let db = open_again(|| new_vault!(path, Span)).unwrap();
+        db.put(&Span { start: 1, end: 2 }).unwrap();
+    }
+    // Fields are stored by position, so values would be read back with their fields swapped.
+    match open_again(|| new_vault!(path, SwappedSpan)) {
+        Err(VaultError::SchemaMismatch(mismatches)) => {
+            assert_eq!(mismatches, vec![SchemaMismatch {
+                type_name: "Span",
+                differences: vec![
+                    "field `start` moved from position 0 to 1".to_owned(),
+                    "field `end` moved from position 1 to 0".to_owned(),
+                ],
+            }]);
+        },
+        other => panic!("Opening with reordered fields should fail: {:?}", other.err()),
+    }
+}
+
+#[test]
+fn test_migrations() {
+    let path = &test_path("test_db_migrations");
+    let (household_id, base_id) = {
+        let db = open_again(|| new_vault!(path, HouseholdV1, PersonV1, AddressV1, BaseStruct)).unwrap();
+        let head = PersonV1 { age: 30, address: Box::new(AddressV1 { number: 5 }) };
+        let household_id = db.put(&HouseholdV1 { size: 2, head: Box::new(head.clone()) }).unwrap();
+        db.set_root("household", household_id).unwrap();
+        db.set_root("household", db.put(&HouseholdV1 { size: 3, head: Box::new(head) }).unwrap()).unwrap();
+        (household_id, db.put(&BaseStruct { foo: 7 }).unwrap())
+    };
+    assert!(matches!(open_again(|| new_vault!(path, Household, Person, Address, BaseStruct)), Err(VaultError::SchemaMismatch(_))));
+
+    let types = || vec![TypeInfo::of::<Household>(), TypeInfo::of::<Person>(), TypeInfo::of::<Address>(), TypeInfo::of::<BaseStruct>()];
+    let migrations = || Migrations::new()
+        .convert::<AddressV1, Address>(|old, _ctx| Ok(Address { number: old.number, floor: 0 }))
+        .convert::<PersonV1, Person>(|old, ctx| Ok(Person { age: old.age, address: ctx.get(old.address)?, verified: false }));
+    let head = Person { age: 30, address: Box::new(Address { number: 5, floor: 0 }), verified: false };
+    {
+        let db = open_again(|| open_vault(path, types(), VaultOptions::new().migrations(migrations()))).unwrap();
+        // Both households refer to the same converted person, and the old values are gone.
+        let mut households: Vec<Household> = db.scan_all::<Household>().unwrap()
+            .map(|result| *result.unwrap().0)
+            .collect();
+        households.sort_by_key(|household| household.size);
+        assert_eq!(households, vec![
+            Household { size: 2, head: Box::new(head.clone()) },
+            Household { size: 3, head: Box::new(head.clone()) },
+        ]);
+        assert_eq!(db.count::<Person>().unwrap(), 1);
+        assert_eq!(db.count::<Address>().unwrap(), 1);
+        assert_eq!(db.get::<Household>(household_id).unwrap(), None);
+        // Values that don't refer to converted types keep their ids.
+        assert_eq!(db.get::<BaseStruct>(base_id).unwrap(), Some(BaseStruct { foo: 7 }));
+        // The converted person is referred to by both households, and the old person by nothing.
+        let (_, person_id) = db.scan_all::<Person>().unwrap().next().unwrap().unwrap();
+        assert_eq!(db.referrers(person_id).count(), 2);
+        assert_eq!(db.referrers_of_type::<Household>(person_id).unwrap().count(), 2);
+        // The converted values take the place of the old ones in the change log.
+        assert_eq!(db.changes_from::<Household>(0).unwrap().count(), 2);
+        // Stored and newly put values agree on ids.
+        assert_eq!(db.count::<Household>().unwrap(), 2);
+        let new_household_id = db.put(&Household { size: 2, head: Box::new(head.clone()) }).unwrap();
+        assert_eq!(db.count::<Household>().unwrap(), 2);
+        // Roots and their history point at the converted values.
+        assert_eq!(db.get_root::<Household>("household").unwrap(), Some(Household { size: 3, head: Box::new(head.clone()) }));
+        let history: Vec<ValueId> = db.root_history("household").unwrap().map(Result::unwrap).collect();
+        assert_eq!(history[0], new_household_id);
+        assert!(matches!(db.remove(history[1]), Err(VaultError::StillReferenced(_))));
+    }
+    // The referrers entries of the old values are removed with them: only those of the households
+    // and of the converted person are left.
+    assert_eq!(tree_len(path, "referrers"), 3);
+    // The new schemas are recorded, so the vault opens without the conversions from now on, and
+    // they are not applied again if they are still configured.
+    {
+        let db = open_again(|| new_vault!(path, Household, Person, Address, BaseStruct)).unwrap();
+        assert_eq!(db.count::<Person>().unwrap(), 1);
+    }
+    let db = open_again(|| open_vault(path, types(), VaultOptions::new().migrations(migrations()))).unwrap();
+    assert_eq!(db.count::<Household>().unwrap(), 2);
+    // References are recounted after migrating, roots included, so once the root points elsewhere
+    // removing both households removes the rest.
+    assert!(matches!(db.remove(db.root_id("household").unwrap().unwrap()), Err(VaultError::StillReferenced(_))));
+    db.set_root("household", base_id).unwrap();
+    let household_ids: Vec<ValueId> = db.scan_all::<Household>().unwrap().map(|result| result.unwrap().1).collect();
+    for id in household_ids {
+        assert!(db.remove(id).unwrap());
+    }
+    assert_eq!(db.count::<Person>().unwrap(), 0);
+    assert_eq!(db.count::<Address>().unwrap(), 0);
+}
+
+#[test]
+fn test_indexes() {
+    let path = &test_path("test_db_indexes");
+    {
+        let db = open_again(|| new_vault!(path, UnindexedReading, BaseStruct)).unwrap();
+        for i in 0..10 {
+            db.put(&UnindexedReading { sensor: i, value: i % 3, base: Box::new(BaseStruct { foo: i % 2 }) }).unwrap();
+        }
+    }
+    // Values stored before a field was indexed are indexed when the vault is opened.
+    let db = open_again(|| new_vault!(path, Reading, BaseStruct)).unwrap();
+    let sensors = |query: &ReadingQuery| -> Vec<u32> {
+        let mut sensors: Vec<u32> = db.query(query).unwrap().map(|result| result.unwrap().0.sensor).collect();
+        sensors.sort();
+        sensors
+    };
+    assert_eq!(db.access_path(&Reading::query().value(1)).unwrap(), AccessPath::Index { field: 1 });
+    assert_eq!(sensors(&Reading::query().value(1)), vec![1, 4, 7]);
+    let even = BaseStruct { foo: 0 };
+    assert_eq!(db.access_path(&Reading::query().base(&even)).unwrap(), AccessPath::Index { field: 2 });
+    assert_eq!(sensors(&Reading::query().base(&even)), vec![0, 2, 4, 6, 8]);
+    assert_eq!(sensors(&Reading::query().value(1).base(&even)), vec![4]);
+    // Leading fields are used before indexes.
+    assert_eq!(db.access_path(&Reading::query().sensor(4).value(1)).unwrap(), AccessPath::Prefix { fields: 2 });
+    assert_eq!(db.access_path(&Reading::query().sensor(4).base(&even)).unwrap(), AccessPath::Prefix { fields: 1 });
+    assert_eq!(sensors(&Reading::query().sensor(4).base(&even)), vec![4]);
+    assert_eq!(db.access_path(&Reading::query()).unwrap(), AccessPath::Prefix { fields: 0 });
+
+    // New values are indexed as they are put.
+    db.put(&Reading { sensor: 20, value: 1, base: Box::new(even.clone()) }).unwrap();
+    assert_eq!(sensors(&Reading::query().value(1)), vec![1, 4, 7, 20]);
+    assert_eq!(sensors(&Reading::query().value(1).base(&even)), vec![4, 20]);
+    assert_eq!(sensors(&Reading::query().value(5)), vec![]);
+}
+
+#[test]
+fn test_range_scans() {
+    let path = &test_path("test_db_ranges");
+    let db = open_again(|| new_vault!(path, Measurement)).unwrap();
+    let measurements = [(-300, -12.5, 7), (-2, 0.0, 300), (-1, -0.5, 256), (0, 3.25, 1), (1, 21.0, 0), (70000, -40.0, 65535)];
+    for (offset, celsius, count) in measurements {
+        db.put(&Measurement { offset, celsius, count }).unwrap();
+    }
+    fn offsets(values: impl Iterator<Item = Result<(Box<Measurement>, ValueId), VaultError>>) -> Vec<i32> {
+        values.map(|result| result.unwrap().0.offset).collect()
+    }
+
+    // The leading field, signed, orders the values themselves.
+    assert_eq!(offsets(db.scan_range::<Measurement, i32>("offset", -2..1).unwrap()), vec![-2, -1, 0]);
+    assert_eq!(offsets(db.scan_range::<Measurement, i32>("offset", ..).unwrap()), vec![-300, -2, -1, 0, 1, 70000]);
+    assert_eq!(offsets(db.scan_range::<Measurement, i32>("offset", (Bound::Excluded(0), Bound::Unbounded)).unwrap()), vec![1, 70000]);
+    assert_eq!(offsets(db.scan_range::<Measurement, i32>("offset", ..=-300).unwrap()), vec![-300]);
+    assert_eq!(offsets(db.scan_range::<Measurement, i32>("offset", (Bound::Included(10), Bound::Excluded(1))).unwrap()), vec![]);
+    // An indexed float field is read in order from its index.
+    assert_eq!(offsets(db.scan_range::<Measurement, f64>("celsius", -12.5..=3.25).unwrap()), vec![-300, -1, -2, 0]);
+    assert_eq!(offsets(db.scan_range::<Measurement, f64>("celsius", ..).unwrap()), vec![70000, -300, -1, -2, 0, 1]);
+    // Any other field is sorted after reading every value.
+    assert_eq!(offsets(db.scan_range::<Measurement, u16>("count", 1..=300).unwrap()), vec![0, -300, -1, -2]);
+
+    assert!(matches!(db.scan_range::<Measurement, u32>("offset", ..), Err(VaultError::UnknownField { type_name: "Measurement", .. })));
+    assert!(matches!(db.scan_range::<Measurement, i32>("missing", ..), Err(VaultError::UnknownField { .. })));
+}
+
+#[test]
+fn test_referrers() {
+    let path = &test_path("test_db_referrers");
+    let db = open_again(|| new_vault!(path, Household, Person, Address, TestStruct, BaseStruct)).unwrap();
+    let address = Address { number: 5, floor: 1 };
+    let alice = Person { age: 30, address: Box::new(address.clone()), verified: true };
+    let bob = Person { age: 40, address: Box::new(address.clone()), verified: false };
+    let household_id = db.put(&Household { size: 2, head: Box::new(alice.clone()) }).unwrap();
+    let bob_id = db.put(&bob).unwrap();
+    let address_id = db.put(&address).unwrap();
+    let alice_id = db.put(&alice).unwrap();
+    let sorted = |mut ids: Vec<ValueId>| { ids.sort(); ids };
+
+    // Shared values are referred to by every value they are nested in, but only directly.
+    let referrers = |id| sorted(db.referrers(id).map(Result::unwrap).collect());
+    assert_eq!(referrers(address_id), sorted(vec![alice_id, bob_id]));
+    assert_eq!(referrers(alice_id), vec![household_id]);
+    assert_eq!(referrers(household_id), vec![]);
+
+    let mut people: Vec<u32> = db.referrers_of_type::<Person>(address_id).unwrap().map(|result| result.unwrap().0.age).collect();
+    people.sort();
+    assert_eq!(people, vec![30, 40]);
+    assert_eq!(db.referrers_of_type::<Household>(address_id).unwrap().count(), 0);
+
+    // A stored Option refers to the values nested in what it holds, but is not a value of that type.
+    let base = BaseStruct { foo: 10 };
+    let base_id = db.put(&base).unwrap();
+    let inner = TestStruct { field: 1, base_field: Box::new(base.clone()), rec_field: None };
+    let outer_id = db.put(&TestStruct { field: 2, base_field: Box::new(base), rec_field: Some(Box::new(inner)) }).unwrap();
+    let base_referrers: Vec<u32> = db.referrers_of_type::<TestStruct>(base_id).unwrap().map(|result| result.unwrap().0.field).collect();
+    assert_eq!(base_referrers, vec![2]);
+    assert!(referrers(base_id).contains(&outer_id));
+    assert_eq!(referrers(base_id).len(), 2);
+    db.clear().unwrap();
+    assert_eq!(referrers(address_id), vec![]);
+}
+
+#[test]
+fn test_deep_queries() {
+    let path = &test_path("test_db_deep_queries");
+    let db = open_again(|| new_vault!(path, Household, Person, Address, Reading, Labelled, BaseStruct)).unwrap();
+    for (size, age, number) in [(1, 20, 5), (2, 30, 5), (3, 40, 6), (4, 20, 7)] {
+        let head = Person { age, address: Box::new(Address { number, floor: 0 }), verified: age > 25 };
+        db.put(&Household { size, head: Box::new(head) }).unwrap();
+    }
+    for i in 0..6 {
+        db.put(&Reading { sensor: i, value: i % 3, base: Box::new(BaseStruct { foo: i % 2 }) }).unwrap();
+        db.put(&Labelled { base: Box::new(BaseStruct { foo: i % 3 }), label: i }).unwrap();
+    }
+
+    // Nested queries are followed through the referrers of the values they find.
+    let query = Household::query().head_matching(Person::query().address_matching(Address::query().number(5)));
+    assert_eq!(db.access_path(&query).unwrap(), AccessPath::Nested { field: 1 });
+    let mut sizes: Vec<u32> = db.query(&query).unwrap().map(|result| result.unwrap().0.size).collect();
+    sizes.sort();
+    assert_eq!(sizes, vec![1, 2]);
+    let query = Household::query().head_matching(Person::query().age(20).address_matching(Address::query().number(7)));
+    let sizes: Vec<u32> = db.query(&query).unwrap().map(|result| result.unwrap().0.size).collect();
+    assert_eq!(sizes, vec![4]);
+    let query = Household::query().head_matching(Person::query().age(50));
+    assert_eq!(db.query(&query).unwrap().count(), 0);
+    // Other constraints still come first, and nested ones are checked on what they find.
+    let query = Household::query().size(2).head_matching(Person::query().verified(true));
+    assert_eq!(db.access_path(&query).unwrap(), AccessPath::Prefix { fields: 1 });
+    assert_eq!(db.query(&query).unwrap().count(), 1);
+    let query = Household::query().size(1).head_matching(Person::query().verified(true));
+    assert_eq!(db.query(&query).unwrap().count(), 0);
+
+    // Indexed fields are looked up by the ids that were found.
+    let query = Reading::query().base_matching(BaseStruct::query().foo(1));
+    assert_eq!(db.access_path(&query).unwrap(), AccessPath::Nested { field: 2 });
+    let mut sensors: Vec<u32> = db.query(&query).unwrap().map(|result| result.unwrap().0.sensor).collect();
+    sensors.sort();
+    assert_eq!(sensors, vec![1, 3, 5]);
+
+    // Leading fields are looked up by prefix.
+    let query = Labelled::query().base_matching(BaseStruct::query().foo(2)).label(5);
+    assert_eq!(db.access_path(&query).unwrap(), AccessPath::Nested { field: 0 });
+    let labels: Vec<u32> = db.query(&query).unwrap().map(|result| result.unwrap().0.label).collect();
+    assert_eq!(labels, vec![5]);
+    let query = Labelled::query().base_matching(BaseStruct::query());
+    assert_eq!(db.query(&query).unwrap().count(), 6);
+}
+
+#[test]
+fn test_datalog() {
+    let path = &test_path("test_db_datalog");
+    let db = open_again(|| new_vault!(path, DependsOn, Requires, HeadAge, Household, Person, Address)).unwrap();
+    for (package, dependency) in [(1, 2), (2, 3), (3, 4), (5, 6), (6, 5)] {
+        db.put(&DependsOn { package, dependency }).unwrap();
+    }
+    let requires = Program::new()
+        .rule(Rule::derive::<Requires>(["p", "d"]).when::<DependsOn>(["p", "d"]))
+        .rule(Rule::derive::<Requires>(["p", "d"]).when::<DependsOn>(["p", "x"]).when::<Requires>(["x", "d"]));
+    let pairs = || {
+        let mut pairs: Vec<(u32, u32)> = db.query(&Requires::query()).unwrap()
+            .map(|result| result.unwrap().0)
+            .map(|requires| (requires.package, requires.dependency))
+            .collect();
+        pairs.sort();
+        pairs
+    };
+
+    // Recursive rules derive the transitive closure, cycles included.
+    assert_eq!(db.evaluate(&requires).unwrap(), 10);
+    assert_eq!(pairs(), vec![(1, 2), (1, 3), (1, 4), (2, 3), (2, 4), (3, 4), (5, 5), (5, 6), (6, 5), (6, 6)]);
+    // Derived facts are stored, so evaluating again only derives what is new.
+    assert_eq!(db.evaluate(&requires).unwrap(), 0);
+    db.put(&DependsOn { package: 4, dependency: 5 }).unwrap();
+    assert_eq!(db.evaluate(&requires).unwrap(), 8);
+    assert!(pairs().contains(&(1, 6)));
+    let query = Requires::query().package(2);
+    assert_eq!(db.query(&query).unwrap().count(), 4);
+
+    // Fixed values, and joins of ids with the fields that refer to them.
+    for (size, age) in [(1, 20), (2, 30), (3, 40)] {
+        let head = Person { age, address: Box::new(Address { number: 5, floor: 0 }), verified: true };
+        db.put(&Household { size, head: Box::new(head) }).unwrap();
+    }
+    let head_ages = Program::new().rule(Rule::derive::<HeadAge>(["size", "age"])
+        .when::<Household>(["size", "head"])
+        .when_id::<Person>("head", ["age".into(), Term::Any, Term::value(&true)]));
+    assert_eq!(db.evaluate(&head_ages).unwrap(), 3);
+    let query = HeadAge::query().size(2);
+    assert_eq!(db.query(&query).unwrap().next().unwrap().unwrap().0, Box::new(HeadAge { size: 2, age: 30 }));
+
+    // Derived values have the ids they would get from `put`, nested values included.
+    let (derived, derived_id) = db.query(&Requires::query().package(1).dependency(6)).unwrap().next().unwrap().unwrap();
+    assert_eq!(db.put(&*derived).unwrap(), derived_id);
+    let households = Program::new().rule(Rule::derive::<Household>(vec![Term::value(&4u32), "head".into()])
+        .when_id::<Person>("head", ["_", "_", "_"]));
+    assert_eq!(db.evaluate(&households).unwrap(), 3);
+    let head = Person { age: 30, address: Box::new(Address { number: 5, floor: 0 }), verified: true };
+    let household_id = db.put(&Household { size: 4, head: Box::new(head) }).unwrap();
+    let found: Vec<ValueId> = db.query(&Household::query().size(4)).unwrap().map(|result| result.unwrap().1).collect();
+    assert!(found.contains(&household_id));
+    assert_eq!(db.count::<Household>().unwrap(), 6);
+
+    // Rules that cannot be evaluated are rejected before anything is derived.
+    let invalid = |rule| matches!(db.evaluate(&Program::new().rule(rule)), Err(VaultError::InvalidRule(_)));
+    assert!(invalid(Rule::derive::<Requires>(["p", "q"]).when::<DependsOn>(["p", "d"])));
+    assert!(invalid(Rule::derive::<Requires>(["p", "_"]).when::<DependsOn>(["p", "d"])));
+    assert!(invalid(Rule::derive::<Requires>(["p", "d"]).when::<DependsOn>(["p", "d", "e"])));
+    assert!(invalid(Rule::derive::<HeadAge>(["size", "head"]).when::<Household>(["size", "head"])));
+    assert!(invalid(Rule::derive::<Requires>(["p", "d"]).when::<DependsOn>([Term::value(&1u8), "d".into()])));
+    // Heads can only refer to stored values.
+    let removed = db.put(&Address { number: 9, floor: 9 }).unwrap();
+    assert!(db.remove(removed).unwrap());
+    assert!(invalid(Rule::derive::<Person>([Term::value(&50u32), Term::id(removed), Term::value(&true)])));
+    assert_eq!(db.count::<Person>().unwrap(), 3);
+}
+
+#[test]
+fn test_remove() {
+    let path = &test_path("test_db_remove");
+    let open = || open_again(|| new_vault!(path, Household, Person, Address));
+    let db = open().unwrap();
+    let address = Address { number: 5, floor: 1 };
+    let alice = Person { age: 30, address: Box::new(address.clone()), verified: true };
+    let bob = Person { age: 40, address: Box::new(address.clone()), verified: false };
+    let household_id = db.put(&Household { size: 2, head: Box::new(alice.clone()) }).unwrap();
+    let bob_id = db.put(&bob).unwrap();
+    let alice_id = db.put(&alice).unwrap();
+    let address_id = db.put(&address).unwrap();
+
+    // Values that others refer to stay until the last of them is removed.
+    assert!(matches!(db.remove(alice_id), Err(VaultError::StillReferenced(id)) if id == alice_id));
+    assert!(db.remove(household_id).unwrap());
+    assert_eq!(db.get::<Household>(household_id).unwrap(), None);
+    assert!(!db.remove(household_id).unwrap());
+    assert_eq!(db.get::<Person>(alice_id).unwrap(), None);
+    assert_eq!(db.get::<Address>(address_id).unwrap(), Some(address.clone()));
+    assert_eq!(db.referrers(address_id).map(Result::unwrap).collect::<Vec<_>>(), vec![bob_id]);
+    let query = Person::query().age(30);
+    assert_eq!(db.query(&query).unwrap().count(), 0);
+
+    // The counts survive reopening.
+    drop(db);
+    let db = open().unwrap();
+    let household_id = db.put(&Household { size: 3, head: Box::new(bob.clone()) }).unwrap();
+    assert!(matches!(db.remove(bob_id), Err(VaultError::StillReferenced(_))));
+    assert!(db.remove(household_id).unwrap());
+    assert_eq!(db.count::<Person>().unwrap(), 0);
+    assert_eq!(db.count::<Address>().unwrap(), 0);
+}
+
+#[test]
+fn test_gc() {
+    let path = &test_path("test_db_gc");
+    let db = open_again(|| new_vault!(path, Household, Person, Address)).unwrap();
+    let address = Address { number: 5, floor: 1 };
+    let alice = Person { age: 30, address: Box::new(address.clone()), verified: true };
+    let bob = Person { age: 40, address: Box::new(Address { number: 6, floor: 0 }), verified: false };
+    let household_id = db.put(&Household { size: 2, head: Box::new(alice.clone()) }).unwrap();
+    let old_household_id = db.put(&Household { size: 1, head: Box::new(bob.clone()) }).unwrap();
+    let address_id = db.put(&address).unwrap();
+    let unrelated_id = db.put(&Address { number: 7, floor: 2 }).unwrap();
+
+    // A dry run removes nothing, and reports the same as the real run.
+    let report = db.gc([household_id], true).unwrap();
+    assert_eq!(report.orphans, 4);
+    assert!(report.bytes_reclaimed > 0);
+    assert_eq!(db.count::<Person>().unwrap(), 2);
+    assert_eq!(db.gc([household_id], false).unwrap(), report);
+
+    // Everything nested in a root survives, whether or not it was put on its own.
+    assert_eq!(db.get::<Household>(household_id).unwrap(), Some(Household { size: 2, head: Box::new(alice) }));
+    assert_eq!(db.referrers(address_id).count(), 1);
+    assert_eq!(db.get::<Household>(old_household_id).unwrap(), None);
+    assert_eq!(db.get::<Address>(unrelated_id).unwrap(), None);
+    assert_eq!(db.count::<Person>().unwrap(), 1);
+    assert_eq!(db.count::<Address>().unwrap(), 1);
+    assert_eq!(db.gc([household_id], false).unwrap(), GcReport::default());
+
+    // Values removed by gc are counted as no longer referring to anything.
+    assert!(db.remove(household_id).unwrap());
+    assert_eq!(db.count::<Address>().unwrap(), 0);
+    assert!(matches!(db.gc([household_id], true), Err(VaultError::MissingId(_))));
+}
+
+#[test]
+fn test_gc_without_reference_counts() {
+    let path = &test_path("test_db_gc_uncounted");
+    let open = |reference_counts| open_again(|| open_vault(path, vec![TypeInfo::of::<Household>(), TypeInfo::of::<Person>(), TypeInfo::of::<Address>()], VaultOptions::new().reference_counts(reference_counts)));
+    let person = |age| Person { age, address: Box::new(Address { number: 5, floor: 1 }), verified: true };
+    let (kept_id, orphan_id) = {
+        let db = open(false).unwrap();
+        let kept_id = db.put(&Household { size: 2, head: Box::new(person(30)) }).unwrap();
+        let orphan_id = db.put(&Household { size: 1, head: Box::new(person(40)) }).unwrap();
+        db.bulk_writer().put(&person(50)).unwrap();
+        assert!(matches!(db.remove(orphan_id), Err(VaultError::Unsupported(_))));
+
+        // gc removes what the roots don't lead to, and everything about it.
+        db.set_root("kept", kept_id).unwrap();
+        assert_eq!(db.gc([], false).unwrap().orphans, 3);
+        assert_eq!(db.count::<Person>().unwrap(), 1);
+        assert_eq!(db.count::<Address>().unwrap(), 1);
+        assert_eq!(db.changes_from::<Household>(0).unwrap().count(), 1);
+        (kept_id, orphan_id)
+    };
+    assert_eq!(tree_len(path, "refcounts"), 0);
+    assert_eq!(tree_len(path, "referrers"), 2);
+
+    // Turning the counts on counts every reference, roots included.
+    let db = open(true).unwrap();
+    assert_eq!(db.get::<Household>(orphan_id).unwrap(), None);
+    assert!(matches!(db.remove(kept_id), Err(VaultError::StillReferenced(_))));
+    let address_id = db.put(&Address { number: 5, floor: 1 }).unwrap();
+    db.set_root("kept", address_id).unwrap();
+    assert!(db.remove(kept_id).unwrap());
+    assert_eq!(db.count::<Person>().unwrap(), 0);
+    assert_eq!(db.count::<Address>().unwrap(), 1);
+}
+
+#[test]
+fn test_roots() {
+    let path = &test_path("test_db_roots");
+    let db = open_again(|| new_vault!(path, Person, Address)).unwrap();
+    let person = |age| Person { age, address: Box::new(Address { number: 5, floor: 1 }), verified: true };
+    let first_id = db.put(&person(30)).unwrap();
+    let second_id = db.put(&person(31)).unwrap();
+    let third_id = db.put(&person(32)).unwrap();
+
+    assert_eq!(db.get_root::<Person>("current").unwrap(), None);
+    assert!(matches!(db.set_root("current", ValueId::from_slice(&[0; 16]).unwrap()), Err(VaultError::MissingId(_))));
+    db.set_root("current", first_id).unwrap();
+    assert_eq!(db.get_root::<Person>("current").unwrap(), Some(person(30)));
+    assert!(matches!(db.get_root::<Address>("current"), Err(VaultError::WrongType { .. })));
+
+    // Updates only go through if the root still points where the writer expects.
+    assert!(!db.update_root("current", None, second_id).unwrap());
+    assert!(!db.update_root("current", Some(third_id), second_id).unwrap());
+    assert!(db.update_root("current", Some(first_id), second_id).unwrap());
+    assert!(db.update_root("next", None, third_id).unwrap());
+    assert_eq!(db.root_id("current").unwrap(), Some(second_id));
+    let history: Vec<ValueId> = db.root_history("current").unwrap().map(Result::unwrap).collect();
+    assert_eq!(history, vec![first_id, second_id]);
+    let roots: Vec<(String, ValueId)> = db.roots().map(Result::unwrap).collect();
+    assert_eq!(roots, vec![("current".to_owned(), second_id), ("next".to_owned(), third_id)]);
+
+    // The values the roots point at now survive gc, but not those they pointed at before.
+    assert_eq!(db.gc([], false).unwrap().orphans, 1);
+    assert_eq!(db.get::<Person>(first_id).unwrap(), None);
+    assert_eq!(db.get_root::<Person>("current").unwrap(), Some(person(31)));
+    assert_eq!(db.count::<Address>().unwrap(), 1);
+
+    // Values that roots point at can't be removed, on their own or with what they are nested in.
+    assert!(matches!(db.remove(second_id), Err(VaultError::StillReferenced(id)) if id == second_id));
+    let address_id = db.put(&Address { number: 5, floor: 1 }).unwrap();
+    db.set_root("address", address_id).unwrap();
+    db.set_root("next", second_id).unwrap();
+    assert!(db.remove(third_id).unwrap());
+    assert_eq!(db.get::<Address>(address_id).unwrap(), Some(Address { number: 5, floor: 1 }));
+    // Once no root points at a value any more, it can be removed.
+    db.set_root("current", address_id).unwrap();
+    db.set_root("next", address_id).unwrap();
+    assert!(db.remove(second_id).unwrap());
+    assert_eq!(db.count::<Address>().unwrap(), 1);
+}
+
+#[test]
+fn test_change_feed() {
+    let path = &test_path("test_db_change_feed");
+    let open = || open_again(|| new_vault!(path, TestStruct, BaseStruct));
+    let db = open().unwrap();
+    let test_struct = |field| TestStruct { field, base_field: Box::new(BaseStruct { foo: 1 }), rec_field: None };
+    let timeout = std::time::Duration::from_millis(100);
+    let first_id = db.put(&test_struct(1)).unwrap();
+
+    // Subscribers see the values of their type that are stored after they subscribe, once.
+    let mut subscription = db.subscribe::<TestStruct>().unwrap();
+    let second_id = db.put(&test_struct(2)).unwrap();
+    db.put(&test_struct(2)).unwrap();
+    db.put(&BaseStruct { foo: 2 }).unwrap();
+    let change = subscription.next_timeout(timeout).unwrap().unwrap();
+    assert_eq!((change.id, *change.value), (second_id, test_struct(2)));
+    assert!(subscription.next_timeout(timeout).is_none());
+    drop(subscription);
+
+    // The change log lets a consumer resume after the last change it processed.
+    let fields = |changes: &[Change<TestStruct>]| changes.iter().map(|change| change.value.field).collect::<Vec<_>>();
+    let changes: Vec<Change<TestStruct>> = db.changes_from(0).unwrap().map(Result::unwrap).collect();
+    assert_eq!(fields(&changes), vec![1, 2]);
+    assert_eq!(changes[0].id, first_id);
+    let checkpoint = changes[1].sequence;
+    drop(db);
+    let db = open().unwrap();
+    db.put(&test_struct(3)).unwrap();
+    let resumed: Vec<Change<TestStruct>> = db.changes_from(checkpoint + 1).unwrap().map(Result::unwrap).collect();
+    assert_eq!(fields(&resumed), vec![3]);
+    // Nested values are logged under their own type.
+    assert_eq!(db.changes_from::<BaseStruct>(0).unwrap().count(), 2);
+
+    // Removing a value removes its entry: two entries of each type are left.
+    assert!(db.remove(resumed[0].id).unwrap());
+    drop(db);
+    assert_eq!(tree_len(path, "change_log"), 4);
+}
+
+#[test]
+fn test_subscriptions_see_other_handles() {
+    let path = &test_path("test_db_other_handles");
+    let backend = open_backend(path).unwrap();
+    let types = || vec![TypeInfo::of::<TestStruct>(), TypeInfo::of::<BaseStruct>()];
+    let db = TypeVault::with_backend(backend.clone(), types(), VaultOptions::new()).unwrap();
+    let other = TypeVault::with_backend(backend, types(), VaultOptions::new()).unwrap();
+    let test_struct = |field| TestStruct { field, base_field: Box::new(BaseStruct { foo: 1 }), rec_field: None };
+    let timeout = std::time::Duration::from_millis(100);
+
+    // Subscriptions watch the change log, which every handle writes to, in transactions or in bulk.
+    let mut subscription = db.subscribe::<TestStruct>().unwrap();
+    let put_id = other.put(&test_struct(1)).unwrap();
+    assert_eq!(subscription.next_timeout(timeout).unwrap().unwrap().id, put_id);
+    // The values of a batch may arrive in any order, but their sequence numbers are in order.
+    let (bulk_ids, _) = other.put_many([&test_struct(2), &test_struct(3)]).unwrap();
+    let mut changes: Vec<Change<TestStruct>> = (0..2).map(|_| subscription.next_timeout(timeout).unwrap().unwrap()).collect();
+    changes.sort_by_key(|change| change.sequence);
+    assert_eq!(changes.iter().map(|change| change.id).collect::<Vec<_>>(), bulk_ids);
+    assert!(subscription.next_timeout(timeout).is_none());
+}
+
+#[test]
+fn test_tuples_are_not_their_first_element() {
+    let path = &test_path("test_db_tuples");
+    let db = open_again(|| new_vault!(path, Pair, Reading, BaseStruct, HeadAge)).unwrap();
+    let reading = |value| Reading { sensor: 1, value, base: Box::new(BaseStruct { foo: value }) };
+    let reading_id = db.put(&reading(1)).unwrap();
+    let pair = Pair { pair: (reading(1), reading(2)) };
+    let pair_id = db.put(&pair).unwrap();
+    assert_eq!(db.get::<Pair>(pair_id).unwrap(), Some(pair.clone()));
+
+    // The tuple the pair refers to is not a reading, so it is not counted, scanned, indexed or
+    // logged as one.
+    assert_eq!(db.count::<Reading>().unwrap(), 1);
+    let scanned: Vec<ValueId> = db.scan_all::<Reading>().unwrap().map(|result| result.unwrap().1).collect();
+    assert_eq!(scanned, vec![reading_id]);
+    let found: Vec<ValueId> = db.query(&Reading::query().value(1)).unwrap().map(|result| result.unwrap().1).collect();
+    assert_eq!(found, vec![reading_id]);
+    assert_eq!(db.query(&Reading::query().value(2)).unwrap().count(), 0);
+    assert_eq!(db.scan_range::<Reading, u32>("sensor", ..).unwrap().count(), 1);
+    assert_eq!(db.changes_from::<Reading>(0).unwrap().count(), 1);
+    assert_eq!(db.count::<BaseStruct>().unwrap(), 2);
+    // Nor is it matched as one by rules.
+    let tuple_id = db.put(&pair.pair).unwrap();
+    let rule = Rule::derive::<HeadAge>(["s", "v"]).when_id::<Reading>(Term::id(tuple_id), ["s", "v", "_"]);
+    assert_eq!(db.evaluate(&Program::new().rule(rule)).unwrap(), 0);
+}
+
+// Polls `future` until it is done, which is enough for the futures of the vault.
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+    loop {
+        match future.as_mut().poll(&mut context) {
+            std::task::Poll::Ready(output) => return output,
+            std::task::Poll::Pending => std::thread::sleep(std::time::Duration::from_millis(1)),
+        }
+    }
+}
+
+#[test]
+fn test_read_only() {
+    let path = &test_path("test_db_read_only");
+    let read_only = || open_again(|| open_vault(path, vec![TypeInfo::of::<Person>(), TypeInfo::of::<Address>()], VaultOptions::new().read_only(true)));
+    // A new vault has to be set up first.
+    assert!(matches!(read_only(), Err(VaultError::ReadOnly)));
+    let address = Address { number: 5, floor: 1 };
+    let alice = Person { age: 30, address: Box::new(address.clone()), verified: true };
+    let alice_id;
+    {
+        let db = open_again(|| new_vault!(path, Person, Address)).unwrap();
+        alice_id = db.put(&alice).unwrap();
+        db.flush().unwrap();
+        block_on(db.flush_async()).unwrap();
+    }
+
+    let db = read_only().unwrap();
+    assert_eq!(db.get::<Person>(alice_id).unwrap(), Some(alice.clone()));
+    assert_eq!(db.query(&Person::query().age(30)).unwrap().count(), 1);
+    assert!(matches!(db.put(&address), Err(VaultError::ReadOnly)));
+    assert!(matches!(db.transaction(|tx| tx.put(&address)), Err(VaultError::ReadOnly)));
+    assert!(matches!(db.remove(alice_id), Err(VaultError::ReadOnly)));
+    assert!(matches!(db.set_root("alice", alice_id), Err(VaultError::ReadOnly)));
+    assert!(matches!(db.clear(), Err(VaultError::ReadOnly)));
+    assert_eq!(db.gc([], true).unwrap().orphans, 2);
+    assert!(matches!(db.gc([], false), Err(VaultError::ReadOnly)));
+    drop(db);
+    // Registering another type would have to write to the vault.
+    let registering = open_again(|| open_vault(path, vec![TypeInfo::of::<Person>(), TypeInfo::of::<Address>(), TypeInfo::of::<BaseStruct>()], VaultOptions::new().read_only(true)));
+    assert!(matches!(registering, Err(VaultError::ReadOnly)));
+    assert_eq!(read_only().unwrap().count::<Person>().unwrap(), 1);
+}
+
+#[test]
+fn test_bulk_writes() {
+    let path = &test_path("test_db_bulk");
+    let db = open_again(|| new_vault!(path, Household, Person, Address)).unwrap();
+    let household = |size: u32| Household {
+        size,
+        head: Box::new(Person { age: size % 3, address: Box::new(Address { number: size % 2, floor: 0 }), verified: true }),
+    };
+    let existing_id = db.put(&household(0)).unwrap();
+    let mut subscription = db.subscribe::<Household>().unwrap();
+
+    // Values are written once, however often they occur, and not at all if they are stored.
+    let households: Vec<Household> = (0..12).chain(0..12).map(household).collect();
+    let (ids, report) = db.put_many(&households).unwrap();
+    assert_eq!(ids[0], existing_id);
+    assert_eq!(ids[5], ids[17]);
+    assert_eq!(report.values, 24);
+    // 11 new households, 5 new persons and 1 new address.
+    assert_eq!(report.written, 17);
+    // Household 0 both times, and its person and address where new households refer to them.
+    // Nothing nested in a skipped value is looked at.
+    assert_eq!(report.already_stored, 2 + 1 + 2);
+    // Every household the second time, and the persons and addresses of new households put earlier.
+    assert_eq!(report.duplicates, 11 + 5 + 2);
+    assert!(report.bytes_written > 0);
+    assert_eq!(db.count::<Household>().unwrap(), 12);
+    assert_eq!(db.count::<Person>().unwrap(), 6);
+    assert_eq!(db.get::<Household>(ids[7]).unwrap(), Some(household(7)));
+    assert_eq!(db.put(&household(7)).unwrap(), ids[7]);
+    assert_eq!(db.query(&Household::query().size(7)).unwrap().count(), 1);
+    let mut changes: Vec<Change<Household>> = (0..11).map(|_| subscription.next().unwrap().unwrap()).collect();
+    changes.sort_by_key(|change| change.sequence);
+    assert_eq!(changes.iter().map(|change| change.id).collect::<Vec<_>>(), ids[1..12]);
+
+    // Small batches, and everything that depends on the reference counts.
+    let mut writer = db.bulk_writer().batch_size(2);
+    let more_ids: Vec<ValueId> = (12..20).map(|size| writer.put(&household(size)).unwrap()).collect();
+    let report = writer.finish().unwrap();
+    assert_eq!((report.values, report.already_stored), (8, 8));
+    assert!(report.values_per_second() > 0.0);
+    assert_eq!(db.bulk_writer().finish().unwrap().values_per_second(), 0.0);
+    assert_eq!(db.count::<Household>().unwrap(), 20);
+    let person_id = db.put(&*household(12).head).unwrap();
+    assert!(matches!(db.remove(person_id), Err(VaultError::StillReferenced(_))));
+    for id in ids.iter().chain(&more_ids).collect::<HashSet<_>>() {
+        assert!(db.remove(*id).unwrap());
+    }
+    assert_eq!(db.count::<Person>().unwrap(), 0);
+    assert_eq!(db.count::<Address>().unwrap(), 0);
+}
+
+#[test]
+fn test_stored_subtrees_are_skipped() {
+    let path = &test_path("test_db_subtrees");
+    let db = open_again(|| new_vault!(path, TestStruct, BaseStruct)).unwrap();
+    let chain = |length: u32| (0..length).fold(None, |rest, field| {
+        Some(Box::new(TestStruct { field, base_field: Box::new(BaseStruct { foo: field % 2 }), rec_field: rest }))
+    }).unwrap();
+    db.put(&*chain(200)).unwrap();
+
+    // Only the new head and the `Some` of the old one are written, and only what they refer to
+    // directly is looked at: the old chain and the bases of both heads.
+    let (_, report) = db.put_many([&*chain(201)]).unwrap();
+    assert_eq!((report.written, report.already_stored, report.duplicates), (2, 3, 0));
+
+    // The same goes for transactions.
+    let mut subscription = db.subscribe::<TestStruct>().unwrap();
+    let longer_id = db.put(&*chain(202)).unwrap();
+    assert_eq!(subscription.next().unwrap().unwrap().id, longer_id);
+    assert!(subscription.next_timeout(std::time::Duration::from_millis(50)).is_none());
+    // Only the heads are stored under the tag of `TestStruct`, the rest of the chain is in `Some`s.
+    assert_eq!(db.count::<TestStruct>().unwrap(), 3);
+    assert_eq!(db.get::<TestStruct>(longer_id).unwrap(), Some(*chain(202)));
+}
+
+// Counts the values it hashes, which is every value that is serialized.
+struct CountingHasher(Arc<AtomicUsize>);
+
+impl ContentHasher for CountingHasher {
+    fn name(&self) -> &'static str {
+        "counting-blake3"
+    }
+
+    fn hash_into(&self, data: &[u8], out: &mut [u8]) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Blake3Hasher.hash_into(data, out);
+    }
+}
+
+// A tree of the given depth with leaves numbered from `first`.
+fn tree(depth: u32, first: u32) -> Shared<Tree> {
+    match depth {
+        0 => Shared::new(Tree::Leaf(first)),
+        _ => Shared::new(Tree::Node(tree(depth - 1, first), tree(depth - 1, first + (1 << (depth - 1))))),
+    }
+}
+
+// A copy of `tree` with leaf number `leaf` set to `value`, which shares every part of it that is
+// not on the path to that leaf.
+fn with_leaf(tree: &Shared<Tree>, leaf: u32, value: u32) -> Shared<Tree> {
+    match &**tree {
+        Tree::Leaf(_) => Shared::new(Tree::Leaf(value)),
+        Tree::Node(left, right) => {
+            let depth = leaf_count(left).trailing_zeros();
+            if leaf >> depth == 0 {
+                Shared::new(Tree::Node(with_leaf(left, leaf, value), right.clone()))
+            } else {
+                Shared::new(Tree::Node(left.clone(), with_leaf(right, leaf - (1 << depth), value)))
+            }
+        },
+    }
+}
+
+fn leaf_count(tree: &Tree) -> u32 {
+    match tree {
+        Tree::Leaf(_) => 1,
+        Tree::Node(left, right) => leaf_count(left) + leaf_count(right),
+    }
+}
+
+#[test]
+fn test_shared_subtrees_are_not_serialized_again() {
+    let path = &test_path("test_db_shared");
+    let hashed = Arc::new(AtomicUsize::new(0));
+    let db = open_again(|| open_vault(path, vec![TypeInfo::of::<Tree>()], VaultOptions::new().hasher(CountingHasher(hashed.clone())))).unwrap();
+    let depth = 10;
+    let values = (2 << depth) - 1;
+    let original = tree(depth, 0);
+    db.put(&original).unwrap();
+    assert_eq!(db.count::<Tree>().unwrap(), values);
+
+    // Only the path to the changed leaf is serialized, with the parts next to it, which are skipped
+    // as they are stored.
+    let path_length = depth as usize + 1;
+    hashed.store(0, Ordering::Relaxed);
+    let changed = with_leaf(&original, 300, 5000);
+    let changed_id = db.put(&changed).unwrap();
+    assert!(hashed.load(Ordering::Relaxed) <= 4 * path_length, "hashed {} values", hashed.load(Ordering::Relaxed));
+    assert_eq!(db.count::<Tree>().unwrap(), values + path_length);
+    assert_eq!(db.get::<Tree>(changed_id).unwrap().as_ref(), Some(&*changed));
+
+    // Parts that are not stored anymore are serialized again.
+    assert!(db.remove(changed_id).unwrap());
+    assert_eq!(db.count::<Tree>().unwrap(), values);
+    hashed.store(0, Ordering::Relaxed);
+    assert_eq!(db.put(&changed).unwrap(), changed_id);
+    assert!(hashed.load(Ordering::Relaxed) <= 4 * path_length, "hashed {} values", hashed.load(Ordering::Relaxed));
+    assert_eq!(db.count::<Tree>().unwrap(), values + path_length);
+    assert_eq!(db.get::<Tree>(changed_id).unwrap().as_ref(), Some(&*changed));
+
+    // Values that are read back remember what they were read from, so the same goes for them.
+    let read = Shared::new(db.get::<Tree>(changed_id).unwrap().unwrap());
+    hashed.store(0, Ordering::Relaxed);
+    let changed_again_id = db.put(&with_leaf(&read, 700, 6000)).unwrap();
+    assert!(hashed.load(Ordering::Relaxed) <= 4 * path_length, "hashed {} values", hashed.load(Ordering::Relaxed));
+    assert_eq!(db.get::<Tree>(changed_again_id).unwrap().as_ref(), Some(&*with_leaf(&changed, 700, 6000)));
+}